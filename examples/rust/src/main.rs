@@ -0,0 +1,85 @@
+//! Example painter in Rust: a colour wheel that turns a little every frame.
+//!
+//! Usage: jeeves-painter [URL] [NAME]
+
+use std::f64::consts::TAU;
+use std::time::Duration;
+
+use jeeves_client::protocol::TILE_PIXELS;
+use jeeves_client::{run, Error, Hello, Paint};
+
+struct Wheel {
+    width: usize,
+    height: usize,
+    turn: f64,
+}
+
+impl Paint for Wheel {
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+    }
+
+    fn paint(&mut self, _tick: Option<u64>) -> Vec<u8> {
+        self.turn = (self.turn + 0.05) % 1.0;
+        let (cx, cy) = (self.width as f64 / 2.0, self.height as f64 / 2.0);
+        let mut pixels = Vec::with_capacity(self.width * self.height * 4);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (dx, dy) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+                let hue = (dy.atan2(dx) / TAU + self.turn).rem_euclid(1.0);
+                let value = 1.0 - (dx.hypot(dy) / cx.max(cy)).min(1.0) * 0.6;
+                pixels.extend(hsv_to_rgb(hue, 1.0, value));
+                pixels.push(255);
+            }
+        }
+        pixels
+    }
+}
+
+/// Hue, saturation and value from 0 to 1, as 8-bit red, green and blue
+fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> [u8; 3] {
+    let channel = |n: f64| {
+        let k = (n + hue * 6.0) % 6.0;
+        let c = value - value * saturation * (k.min(4.0 - k).clamp(0.0, 1.0));
+        (c * 255.0).round() as u8
+    };
+    [channel(5.0), channel(3.0), channel(1.0)]
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let url = args
+        .next()
+        .unwrap_or_else(|| String::from("wss://rse.pagekite.me"));
+    let mut hello = Hello {
+        name: args.next().unwrap_or_else(|| String::from("Rust wheel")),
+        url: String::from("https://github.com/MaybeJustJames/Jeeves"),
+        ..Hello::default()
+    };
+    let mut wheel = Wheel {
+        width: TILE_PIXELS,
+        height: TILE_PIXELS,
+        turn: 0.0,
+    };
+    // Reconnect when the connection drops, picking up the same tile if the server kept it
+    loop {
+        match run(&url, &mut hello, &mut wheel).await {
+            Ok(()) => {
+                println!("Disconnected, reconnecting");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Err(Error::Connection(reason)) => {
+                eprintln!("{}, reconnecting in 5 seconds", reason);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+            // Banned or kicked, which `Paint::error` has already reported
+            Err(Error::Rejected(_)) => std::process::exit(1),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+/************** Jeeves client **************
+ * Connects a painter to a Jeeves server:   *
+ * answers `?`, learns its tile size from   *
+ * `size`, sends a frame for each `p` and   *
+ * acknowledges events. Painters implement  *
+ * `Paint` and hand it to `run`.            *
+ ******************************************/
+
+pub mod protocol;
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use jsonic::json_item::JsonItem;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::protocol::{
+    escape, ACK, ERROR, EVENT, PAINTER, PIXEL_SIZE, RESUME, SEND_ME_PIXELS, SIZE, TILE_PIXELS,
+    WHO_ARE_YOU,
+};
+
+/// Why a painter stopped painting
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The server turned the painter away, e.g. for being banned or naughty. Trying again
+    /// won't help.
+    Rejected(String),
+    /// Connecting failed or the connection dropped: worth trying again shortly
+    Connection(String),
+    /// The server or the painter broke the protocol
+    Protocol(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Rejected(reason) => write!(f, "Rejected by the server: {}", reason),
+            Error::Connection(reason) | Error::Protocol(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// How a painter introduces itself in its `?` reply
+#[derive(Debug, Clone, Default)]
+pub struct Hello {
+    pub name: String,
+    pub url: String,
+    /// The room to paint in, or the server's main room
+    pub room: Option<String>,
+    /// For servers which only let painters with a token in
+    pub token: Option<String>,
+    /// Be polled this often instead of at the room's rate
+    pub interval: Option<Duration>,
+    /// Filled in from the server's `resume` message, so reconnecting gets the same tile back
+    pub resume: Option<String>,
+}
+
+impl Hello {
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"msg\": \"{WHO_ARE_YOU}\", \"{WHO_ARE_YOU}\": \"{PAINTER}\", \"name\": \"{}\", \"url\": \"{}\"",
+            escape(&self.name),
+            escape(&self.url)
+        );
+        for (field, value) in [
+            ("room", &self.room),
+            ("token", &self.token),
+            (RESUME, &self.resume),
+        ] {
+            if let Some(value) = value {
+                json.push_str(&format!(", \"{}\": \"{}\"", field, escape(value)));
+            }
+        }
+        if let Some(interval) = self.interval {
+            json.push_str(&format!(
+                ", \"mode\": \"poll\", \"interval\": {}",
+                interval.as_millis().max(1)
+            ));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// A painter's side of the conversation
+pub trait Paint {
+    /// The server has said how many pixels wide and high the tile is
+    fn resize(&mut self, _width: usize, _height: usize) {}
+
+    /// The next frame, as RGBA pixels filling the tile row by row. `tick` is the
+    /// room's frame number, if the server sent one.
+    fn paint(&mut self, tick: Option<u64>) -> Vec<u8>;
+
+    /// The server complained about something. Unless `warning`, it is about to disconnect.
+    fn error(&mut self, message: &str, warning: bool) {
+        if warning {
+            eprintln!("Warning from the server: {}", message);
+        } else {
+            eprintln!("Rejected by the server: {}", message);
+        }
+    }
+
+    /// Any other message, e.g. `layout`, `full`, `announce` or `event`. Events are
+    /// acknowledged after this returns.
+    fn message(&mut self, _kind: &str, _sent: &JsonItem) {}
+}
+
+/// Where a connection has got to
+struct Session {
+    width: usize,
+    height: usize,
+    /// Why the server said it was disconnecting us
+    rejected: Option<String>,
+}
+
+impl Session {
+    fn new() -> Session {
+        Session {
+            width: TILE_PIXELS,
+            height: TILE_PIXELS,
+            rejected: None,
+        }
+    }
+
+    /// Handle a text message from the server, returning the reply if there is one
+    fn receive(
+        &mut self,
+        text: &str,
+        hello: &mut Hello,
+        painter: &mut impl Paint,
+    ) -> Result<Option<Message>, String> {
+        let sent =
+            jsonic::parse(text).map_err(|_| format!("Server sent invalid JSON: {}", text))?;
+        let dimension = |field: &str| {
+            sent[field]
+                .as_i128()
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| format!("Server sent a {} message without {}", SIZE, field))
+        };
+        Ok(match sent["msg"].as_str() {
+            Some(WHO_ARE_YOU) => Some(Message::Text(hello.to_json())),
+            Some(SIZE) => {
+                (self.width, self.height) = (dimension("w")?, dimension("h")?);
+                painter.resize(self.width, self.height);
+                None
+            }
+            Some(SEND_ME_PIXELS) => {
+                let tick = sent["tick"].as_i128().and_then(|n| u32::try_from(n).ok());
+                let pixels = painter.paint(tick.map(u64::from));
+                let expected = self.width * self.height * PIXEL_SIZE;
+                if pixels.len() != expected {
+                    return Err(format!(
+                        "Painted {} bytes but the tile takes {}",
+                        pixels.len(),
+                        expected
+                    ));
+                }
+                // Tagging the frame lets the server show it alongside the rest of the tick
+                let frame = match tick {
+                    Some(tick) => [&tick.to_be_bytes()[..], &pixels].concat(),
+                    None => pixels,
+                };
+                Some(Message::Binary(frame))
+            }
+            Some(RESUME) => {
+                hello.resume = sent["token"].as_str().map(String::from);
+                None
+            }
+            Some(ERROR) => {
+                let message = sent["error"].as_str().unwrap_or_default();
+                let warning = sent["naughty"].exists();
+                if !warning {
+                    self.rejected = Some(String::from(message));
+                }
+                painter.error(message, warning);
+                None
+            }
+            Some(EVENT) => {
+                painter.message(EVENT, &sent);
+                sent["id"]
+                    .as_i128()
+                    .map(|id| Message::Text(format!("{{\"msg\": \"{ACK}\", \"id\": {}}}", id)))
+            }
+            Some(kind) => {
+                painter.message(kind, &sent);
+                None
+            }
+            None => return Err(format!("Server sent a message without msg: {}", text)),
+        })
+    }
+}
+
+/// Paint on the server at `url` (`ws://` or `wss://`) until it disconnects.
+/// Returns `Ok` after a clean close, which painters may reconnect from.
+pub async fn run(url: &str, hello: &mut Hello, painter: &mut impl Paint) -> Result<(), Error> {
+    let (mut socket, _) = connect_async(url)
+        .await
+        .map_err(|error| Error::Connection(format!("Couldn't connect to {}: {}", url, error)))?;
+    let mut session = Session::new();
+    // A rejection explains any disconnection that follows it
+    let dropped = |session: &Session, reason: String| match &session.rejected {
+        Some(rejected) => Error::Rejected(rejected.clone()),
+        None => Error::Connection(reason),
+    };
+    while let Some(message) = socket.next().await {
+        let message =
+            message.map_err(|error| dropped(&session, format!("Connection failed: {}", error)))?;
+        let reply = match message {
+            Message::Text(text) => session
+                .receive(&text, hello, painter)
+                .map_err(Error::Protocol)?,
+            Message::Close(_) => break,
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            socket.send(reply).await.map_err(|error| {
+                dropped(&session, format!("Couldn't send to the server: {}", error))
+            })?;
+        }
+    }
+    match session.rejected {
+        Some(reason) => Err(Error::Rejected(reason)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::TILE_BYTES;
+
+    #[derive(Default)]
+    struct Flat {
+        size: Option<(usize, usize)>,
+        errors: Vec<(String, bool)>,
+    }
+
+    impl Paint for Flat {
+        fn resize(&mut self, width: usize, height: usize) {
+            self.size = Some((width, height));
+        }
+
+        fn paint(&mut self, _tick: Option<u64>) -> Vec<u8> {
+            let (width, height) = self.size.unwrap_or((TILE_PIXELS, TILE_PIXELS));
+            vec![7; width * height * PIXEL_SIZE]
+        }
+
+        fn error(&mut self, message: &str, warning: bool) {
+            self.errors.push((String::from(message), warning));
+        }
+    }
+
+    #[test]
+    fn test_hello() {
+        let mut hello = Hello {
+            name: String::from("Sam \"the\" painter"),
+            interval: Some(Duration::from_millis(250)),
+            ..Hello::default()
+        };
+        assert_eq!(
+            hello.to_json(),
+            r#"{"msg": "?", "?": "painter", "name": "Sam \"the\" painter", "url": "", "mode": "poll", "interval": 250}"#
+        );
+        hello.interval = None;
+        hello.room = Some(String::from("lab"));
+        assert!(hello.to_json().ends_with(r#""url": "", "room": "lab"}"#));
+    }
+
+    #[test]
+    fn test_conversation() {
+        let mut session = Session::new();
+        let mut hello = Hello::default();
+        let mut painter = Flat::default();
+        let mut receive = |text: &str| session.receive(text, &mut hello, &mut painter);
+
+        assert!(matches!(
+            receive(r#"{"msg": "?"}"#),
+            Ok(Some(Message::Text(_)))
+        ));
+        assert!(receive(r#"{"msg": "size", "w": 40, "h": 40}"#)
+            .unwrap()
+            .is_none());
+        assert!(
+            receive(r#"{"msg": "resume", "token": "abc", "grace_ms": 30000}"#)
+                .unwrap()
+                .is_none()
+        );
+        match receive(r#"{"msg": "p"}"#) {
+            Ok(Some(Message::Binary(frame))) => assert_eq!(frame.len(), TILE_BYTES),
+            other => panic!("unexpected {:?}", other),
+        }
+        match receive(r#"{"msg": "p", "tick": 258}"#) {
+            Ok(Some(Message::Binary(frame))) => assert_eq!(frame[..4], [0, 0, 1, 2]),
+            other => panic!("unexpected {:?}", other),
+        }
+        match receive(r#"{"msg": "event", "id": 3, "kind": "palette"}"#) {
+            Ok(Some(Message::Text(text))) => assert_eq!(text, r#"{"msg": "ack", "id": 3}"#),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(receive(r#"{"msg": "error", "error": "Slow down", "naughty": 1}"#).is_ok());
+        assert!(receive(r#"{"msg": "error", "error": "Banned"}"#).is_ok());
+        assert!(receive("nonsense").is_err());
+
+        assert_eq!(hello.resume.as_deref(), Some("abc"));
+        assert_eq!(session.rejected.as_deref(), Some("Banned"));
+        assert_eq!(
+            painter.errors,
+            [
+                (String::from("Slow down"), true),
+                (String::from("Banned"), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wrong_sized_frame() {
+        let mut session = Session::new();
+        let mut painter = Flat {
+            size: Some((2, 2)),
+            ..Flat::default()
+        };
+        assert!(session
+            .receive(r#"{"msg": "p"}"#, &mut Hello::default(), &mut painter)
+            .is_err());
+    }
+}
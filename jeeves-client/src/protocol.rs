@@ -0,0 +1,64 @@
+/************** Protocol **************
+ * Message names and sizes shared by  *
+ * the server and its clients, so the *
+ * two can't drift apart.             *
+ **************************************/
+
+/// Sent by the server to ask a client's role; the reply has the role under the same name
+pub const WHO_ARE_YOU: &str = "?";
+/// Sent by the server to ask a painter for a frame, and by canvases to ask for the image
+pub const SEND_ME_PIXELS: &str = "p";
+/// The dimensions of a painter's tile, as `"w"` and `"h"`
+pub const SIZE: &str = "size";
+pub const ERROR: &str = "error";
+/// Asked for by painters, and answered with their tile's `"col"` and `"row"` in the grid
+pub const POSITION: &str = "position";
+pub const NEIGHBOURS: &str = "neighbours";
+/// The room's grid and size; painters also get their tile's pixel offset as `"x"` and `"y"`
+pub const LAYOUT: &str = "layout";
+/// Sent to painters waiting for a tile in a full room, with their `"position"` in the queue
+pub const FULL: &str = "full";
+/// Warns a painter taking turns how many `"seconds_left"` its turn has
+pub const TURN: &str = "turn";
+/// The token a painter can send back in its `?` reply to get its tile back after reconnecting,
+/// if it does so within `"grace_ms"`
+pub const RESUME: &str = "resume";
+pub const EVENT: &str = "event";
+pub const ACK: &str = "ack";
+
+pub const PAINTER: &str = "painter";
+pub const CANVAS: &str = "canvas";
+
+/// Pixels along each side of a painter's tile
+pub const TILE_PIXELS: usize = 40;
+/// Bytes in each RGBA pixel
+pub const PIXEL_SIZE: usize = 4;
+pub const TILE_BYTES: usize = TILE_PIXELS * TILE_PIXELS * PIXEL_SIZE;
+/// Bytes of the big-endian tick which may come before a frame's pixels
+pub const TICK_BYTES: usize = 4;
+
+/// Escape a string for embedding between quotes in a JSON message
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(r#"say "hi"\"#), r#"say \"hi\"\\"#);
+        assert_eq!(escape("a\tb"), "a\\u0009b");
+    }
+}
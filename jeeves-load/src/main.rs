@@ -0,0 +1,157 @@
+/************** Jeeves load test **************
+ * Points simulated painters and canvases at a *
+ * server, some misbehaving on purpose, and    *
+ * reports throughput, latency and whether the *
+ * server kept to the protocol. Exits with     *
+ * status 1 if it didn't.                      *
+ **********************************************/
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::simulate::{simulate, Role};
+use crate::stats::Stats;
+
+mod simulate;
+mod stats;
+
+const USAGE: &str = "Usage: jeeves-load [OPTIONS] URL
+
+Options:
+  --painters N      Well-behaved painters (default 8, at most 64 fit in a room)
+  --canvases N      Canvases (default 4)
+  --malformed N     Painters which also send bad frames, bad JSON and unknown messages (default 1)
+  --seconds N       How long to run once everyone has connected (default 10)
+  --canvas-rate N   p requests per second from each canvas (default 4)
+  --ramp-ms N       Milliseconds between starting each client (default 20)
+  --room NAME       Room to join (default the server's main room)
+
+The server's limits must allow the load: --max-connections, --max-unidentified and
+--pixel-request-rate.";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    url: String,
+    painters: usize,
+    canvases: usize,
+    malformed: usize,
+    duration: Duration,
+    canvas_rate: f64,
+    ramp: Duration,
+    room: Option<String>,
+}
+
+impl Options {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut url = None;
+        let mut options = Options {
+            url: String::new(),
+            painters: 8,
+            canvases: 4,
+            malformed: 1,
+            duration: Duration::from_secs(10),
+            canvas_rate: 4.0,
+            ramp: Duration::from_millis(20),
+            room: None,
+        };
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--help" | "-h" => return Err(String::from(USAGE)),
+                "--painters" => options.painters = value(&flag, args.next())?,
+                "--canvases" => options.canvases = value(&flag, args.next())?,
+                "--malformed" => options.malformed = value(&flag, args.next())?,
+                "--seconds" => options.duration = Duration::from_secs(value(&flag, args.next())?),
+                "--canvas-rate" => options.canvas_rate = value(&flag, args.next())?,
+                "--ramp-ms" => options.ramp = Duration::from_millis(value(&flag, args.next())?),
+                "--room" => options.room = Some(value(&flag, args.next())?),
+                _ if flag.starts_with("--") => {
+                    return Err(format!("Unknown option {}\n\n{}", flag, USAGE))
+                }
+                _ if url.is_none() => url = Some(flag),
+                _ => return Err(format!("Only one URL please\n\n{}", USAGE)),
+            }
+        }
+        if options.canvas_rate <= 0.0 {
+            return Err(String::from("--canvas-rate must be positive"));
+        }
+        options.url = url.ok_or_else(|| String::from(USAGE))?;
+        Ok(options)
+    }
+}
+
+fn value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} expects a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("{} got an invalid value: {}", flag, value))
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    let canvas = Role::Canvas(Duration::from_secs_f64(1.0 / options.canvas_rate));
+    let roles = std::iter::repeat_n(Role::Painter, options.painters)
+        .chain(std::iter::repeat_n(Role::Malformed, options.malformed))
+        .chain(std::iter::repeat_n(canvas, options.canvases));
+    let clients = options.painters + options.malformed + options.canvases;
+    println!(
+        "Starting {} painters, {} malformed painters and {} canvases against {}",
+        options.painters, options.malformed, options.canvases, options.url
+    );
+
+    let started = Instant::now();
+    // Everyone stops together, after the ramp up and the test itself
+    let deadline = started + options.ramp * clients as u32 + options.duration;
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let mut tasks = Vec::new();
+    for (index, role) in roles.enumerate() {
+        tasks.push(tokio::spawn(simulate(
+            options.url.clone(),
+            role,
+            index,
+            options.room.clone(),
+            deadline,
+            stats.clone(),
+        )));
+        tokio::time::sleep(options.ramp).await;
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    let stats = stats.lock().unwrap();
+    println!("{}", stats.report(started.elapsed()));
+    if !stats.passed() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::from_args(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_options() {
+        let options = parse("--painters 64 --canvases 20 ws://localhost:8080 --room lab").unwrap();
+        assert_eq!(options.url, "ws://localhost:8080");
+        assert_eq!(
+            (options.painters, options.canvases, options.malformed),
+            (64, 20, 1)
+        );
+        assert_eq!(options.room.as_deref(), Some("lab"));
+        assert!(parse("--painters 8").is_err());
+        assert!(parse("ws://a ws://b").is_err());
+        assert!(parse("--canvas-rate 0 ws://a").is_err());
+        assert!(parse("--shout ws://a").is_err());
+    }
+}
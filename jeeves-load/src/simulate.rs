@@ -0,0 +1,289 @@
+/************** Simulated clients **************
+ * Painters answer `p` with solid frames and    *
+ * canvases ask for the image at a steady rate, *
+ * each checking the server's messages against  *
+ * the protocol as they go. Malformed painters  *
+ * also send a wrong-sized frame, bad JSON and  *
+ * an unknown message, which should each earn   *
+ * a warning rather than a disconnection.       *
+ ***********************************************/
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use jeeves_client::protocol::{
+    escape, ACK, CANVAS, ERROR, EVENT, LAYOUT, PAINTER, PIXEL_SIZE, SEND_ME_PIXELS, SIZE,
+    TILE_BYTES, TILE_PIXELS, WHO_ARE_YOU,
+};
+use jsonic::json_item::JsonItem;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use crate::stats::Stats;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How long a canvas waits for the image before counting its `p` as unanswered
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Painter,
+    /// A painter which sends one of each kind of bad message
+    Malformed,
+    /// A canvas asking for the image this often
+    Canvas(Duration),
+}
+
+impl Role {
+    fn name(self) -> &'static str {
+        match self {
+            Role::Painter => PAINTER,
+            Role::Malformed => "malformed painter",
+            Role::Canvas(_) => CANVAS,
+        }
+    }
+}
+
+/// One simulated client's connection
+struct Client {
+    role: Role,
+    socket: Socket,
+    stats: Arc<Mutex<Stats>>,
+    connected_at: Instant,
+    sized: bool,
+    misbehaved: bool,
+    /// When the canvas's outstanding `p` was sent
+    requested_at: Option<Instant>,
+    next_request: Instant,
+}
+
+/// Run a client until `deadline`, recording what happens in `stats`
+pub async fn simulate(
+    url: String,
+    role: Role,
+    index: usize,
+    room: Option<String>,
+    deadline: Instant,
+    stats: Arc<Mutex<Stats>>,
+) {
+    let connected_at = Instant::now();
+    let socket = match connect_async(&url).await {
+        Ok((socket, _)) => socket,
+        Err(error) => {
+            stats
+                .lock()
+                .unwrap()
+                .fail(format!("{} couldn't connect: {}", role.name(), error));
+            return;
+        }
+    };
+    let mut client = Client {
+        role,
+        socket,
+        stats,
+        connected_at,
+        sized: false,
+        misbehaved: false,
+        requested_at: None,
+        next_request: connected_at,
+    };
+    let hello = match role {
+        Role::Canvas(_) => format!("{{\"msg\": \"{WHO_ARE_YOU}\", \"{WHO_ARE_YOU}\": \"{CANVAS}\""),
+        _ => format!(
+            "{{\"msg\": \"{WHO_ARE_YOU}\", \"{WHO_ARE_YOU}\": \"{PAINTER}\", \"name\": \"load-{}\", \"url\": \"\"",
+            index
+        ),
+    } + &match room {
+        Some(room) => format!(", \"room\": \"{}\"}}", escape(&room)),
+        None => String::from("}"),
+    };
+    let colour = [(index * 37 % 256) as u8, (index * 91 % 256) as u8, 160, 255];
+
+    let closed = loop {
+        let wake = match (role, client.requested_at) {
+            (Role::Canvas(_), Some(requested_at)) => requested_at + REPLY_TIMEOUT,
+            (Role::Canvas(_), None) if client.sized => client.next_request,
+            _ => deadline,
+        };
+        let message =
+            match tokio::time::timeout_at(wake.min(deadline).into(), client.socket.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(error))) => break Some(error.to_string()),
+                Ok(None) => break Some(String::from("closed")),
+                Err(_) if Instant::now() >= deadline => break None,
+                Err(_) => {
+                    client.wake().await;
+                    continue;
+                }
+            };
+        let reply = match message {
+            Message::Text(text) => client.receive(&text, &hello, colour),
+            Message::Binary(frame) => {
+                client.frame(frame);
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        for reply in reply {
+            if client.socket.send(reply).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let mut stats = client.stats.lock().unwrap();
+    match closed {
+        Some(reason) if client.sized => {
+            stats.fail(format!("{} dropped mid-test: {}", role.name(), reason))
+        }
+        Some(reason) => stats.fail(format!("{} never got a size: {}", role.name(), reason)),
+        None if !client.sized => stats.fail(format!("{} never got a size", role.name())),
+        None => {}
+    }
+}
+
+impl Client {
+    fn violation(&self, what: String) {
+        self.stats
+            .lock()
+            .unwrap()
+            .violation(format!("{}: {}", self.role.name(), what));
+    }
+
+    /// A canvas's timer went off: give up on the outstanding `p`, or send the next one
+    async fn wake(&mut self) {
+        if self.requested_at.take().is_some() {
+            self.stats.lock().unwrap().unanswered += 1;
+            self.next_request = Instant::now();
+            return;
+        }
+        let request = format!("{{\"msg\": \"{SEND_ME_PIXELS}\"}}");
+        if self.socket.send(Message::Text(request)).await.is_ok() {
+            self.requested_at = Some(Instant::now());
+        }
+    }
+
+    /// Check a text message and work out the replies to it
+    fn receive(&mut self, text: &str, hello: &str, colour: [u8; 4]) -> Vec<Message> {
+        let Ok(sent) = jsonic::parse(text) else {
+            self.violation(String::from("invalid JSON"));
+            return Vec::new();
+        };
+        match sent["msg"].as_str() {
+            Some(WHO_ARE_YOU) => vec![Message::Text(String::from(hello))],
+            Some(SIZE) => {
+                let dimension = |field: &str| sent[field].as_i128();
+                if dimension("w") != Some(TILE_PIXELS as i128)
+                    || dimension("h") != Some(TILE_PIXELS as i128)
+                {
+                    self.violation(format!("{} isn't {} × {}", SIZE, TILE_PIXELS, TILE_PIXELS));
+                }
+                if !self.sized {
+                    self.sized = true;
+                    let mut stats = self.stats.lock().unwrap();
+                    if let Role::Canvas(_) = self.role {
+                        stats.canvases += 1;
+                        stats.canvas_handshakes.record(self.connected_at.elapsed());
+                    } else {
+                        stats.painters += 1;
+                        stats.painter_handshakes.record(self.connected_at.elapsed());
+                    }
+                }
+                Vec::new()
+            }
+            Some(SEND_ME_PIXELS) => self.poll(&sent, colour),
+            Some(LAYOUT) => {
+                if !["grid", "w", "h", "painters"]
+                    .iter()
+                    .all(|field| sent[*field].as_i128().is_some())
+                {
+                    self.violation(format!("{} without grid, w, h and painters", LAYOUT));
+                }
+                Vec::new()
+            }
+            Some(ERROR) => {
+                let error = sent["error"].as_str().unwrap_or_default();
+                if error.is_empty() {
+                    self.violation(format!("{} without an error", ERROR));
+                }
+                match (self.role, sent["naughty"].exists()) {
+                    (Role::Malformed, true) => self.stats.lock().unwrap().malformed_warned += 1,
+                    (_, true) => self.violation(format!("warned: {}", error)),
+                    // Followed by the connection closing, which is counted as a failure
+                    (_, false) => self.violation(format!("rejected: {}", error)),
+                }
+                Vec::new()
+            }
+            Some(EVENT) => match sent["id"].as_i128() {
+                Some(id) => vec![Message::Text(format!(
+                    "{{\"msg\": \"{ACK}\", \"id\": {}}}",
+                    id
+                ))],
+                None => {
+                    self.violation(format!("{} without an id", EVENT));
+                    Vec::new()
+                }
+            },
+            Some(_) => Vec::new(),
+            None => {
+                self.violation(String::from("message without msg"));
+                Vec::new()
+            }
+        }
+    }
+
+    /// Answer `p` with a frame, and for malformed painters, the bad messages too
+    fn poll(&mut self, sent: &JsonItem, colour: [u8; 4]) -> Vec<Message> {
+        if let Role::Canvas(_) = self.role {
+            self.violation(format!("was sent {}", SEND_ME_PIXELS));
+            return Vec::new();
+        }
+        if !self.sized {
+            self.violation(format!("{} before {}", SEND_ME_PIXELS, SIZE));
+        }
+        if self.role == Role::Malformed && !self.misbehaved {
+            self.misbehaved = true;
+            self.stats.lock().unwrap().malformed_sent += 3;
+            return vec![
+                Message::Binary(vec![0; TILE_BYTES - PIXEL_SIZE]),
+                Message::Text(String::from("{\"msg\": ")),
+                Message::Text(String::from("{\"msg\": \"dance\"}")),
+            ];
+        }
+        let mut frame = Vec::with_capacity(TILE_BYTES + 4);
+        if let Some(tick) = sent["tick"].as_i128().and_then(|n| u32::try_from(n).ok()) {
+            frame.extend(tick.to_be_bytes());
+        }
+        frame.extend(colour.repeat(TILE_BYTES / PIXEL_SIZE));
+        self.stats.lock().unwrap().frames_sent += 1;
+        vec![Message::Binary(frame)]
+    }
+
+    /// Check an image sent to a canvas
+    fn frame(&mut self, frame: Vec<u8>) {
+        let Some(requested_at) = self.requested_at.take() else {
+            self.violation(String::from("sent binary data it didn't ask for"));
+            return;
+        };
+        let round_trip = requested_at.elapsed();
+        if let Role::Canvas(gap) = self.role {
+            self.next_request = requested_at + gap;
+        }
+        let dim = match frame[..] {
+            [high, low, ..] => u16::from_be_bytes([high, low]) as usize,
+            _ => 0,
+        };
+        if dim == 0 || frame.len() != 2 + dim * dim * PIXEL_SIZE {
+            self.violation(format!(
+                "image of {} bytes doesn't match its dimension",
+                frame.len()
+            ));
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.round_trips.record(round_trip);
+        stats.bytes_received += frame.len() as u64;
+    }
+}
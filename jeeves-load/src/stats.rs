@@ -0,0 +1,148 @@
+/************** Load test results **************
+ * What the simulated clients saw, gathered by  *
+ * every connection and summarised at the end.  *
+ * Protocol violations are counted by what went *
+ * wrong, so one bug doesn't flood the report.  *
+ ***********************************************/
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Samples of how long something took
+#[derive(Debug, Default)]
+pub struct Timings {
+    samples: Vec<Duration>,
+}
+
+impl Timings {
+    pub fn record(&mut self, timing: Duration) {
+        self.samples.push(timing);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Mean, 95th percentile and maximum, in ms
+    pub fn summary(&self) -> String {
+        if self.samples.is_empty() {
+            return String::from("none");
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let mean = sorted.iter().sum::<Duration>() / sorted.len() as u32;
+        let p95 = sorted[(sorted.len() * 95).div_ceil(100) - 1];
+        format!(
+            "mean {} ms, p95 {} ms, max {} ms",
+            mean.as_millis(),
+            p95.as_millis(),
+            sorted[sorted.len() - 1].as_millis()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub painters: usize,
+    /// From connecting to receiving `size`
+    pub painter_handshakes: Timings,
+    pub frames_sent: u64,
+    pub canvases: usize,
+    pub canvas_handshakes: Timings,
+    /// From sending `p` to receiving the image
+    pub round_trips: Timings,
+    pub bytes_received: u64,
+    /// `p` requests the server didn't answer in time
+    pub unanswered: u64,
+    /// Malformed messages sent on purpose, and the warnings they earned
+    pub malformed_sent: u64,
+    pub malformed_warned: u64,
+    /// Connections that failed or were dropped, by reason
+    pub failures: BTreeMap<String, u64>,
+    /// Server behaviour that doesn't match the protocol, by what went wrong
+    pub violations: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    pub fn fail(&mut self, reason: String) {
+        *self.failures.entry(reason).or_default() += 1;
+    }
+
+    pub fn violation(&mut self, what: String) {
+        *self.violations.entry(what).or_default() += 1;
+    }
+
+    /// Whether the server kept up its side of the protocol
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+            && self.violations.is_empty()
+            && self.malformed_warned == self.malformed_sent
+    }
+
+    pub fn report(&self, elapsed: Duration) -> String {
+        let per_second = |n: u64| n as f64 / elapsed.as_secs_f64().max(0.001);
+        let mut report = format!(
+            "Painters: {} connected, handshake {}
+  {} frames sent ({:.1}/s)
+Canvases: {} connected, handshake {}
+  {} frames received ({:.1}/s, {:.1} KB/s), {} unanswered
+  round trip {}
+Malformed messages: {} sent, {} warned about
+",
+            self.painters,
+            self.painter_handshakes.summary(),
+            self.frames_sent,
+            per_second(self.frames_sent),
+            self.canvases,
+            self.canvas_handshakes.summary(),
+            self.round_trips.len(),
+            per_second(self.round_trips.len() as u64),
+            per_second(self.bytes_received) / 1000.0,
+            self.unanswered,
+            self.round_trips.summary(),
+            self.malformed_sent,
+            self.malformed_warned,
+        );
+        for (heading, counts) in [
+            ("Failures", &self.failures),
+            ("Protocol violations", &self.violations),
+        ] {
+            report.push_str(&format!("{}: {}\n", heading, counts.values().sum::<u64>()));
+            for (what, count) in counts {
+                report.push_str(&format!("  {} × {}\n", count, what));
+            }
+        }
+        report.push_str(if self.passed() { "PASS" } else { "FAIL" });
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings() {
+        let mut timings = Timings::default();
+        assert_eq!(timings.summary(), "none");
+        for ms in 1..=20 {
+            timings.record(Duration::from_millis(ms));
+        }
+        assert_eq!(timings.summary(), "mean 10 ms, p95 19 ms, max 20 ms");
+    }
+
+    #[test]
+    fn test_passed() {
+        let mut stats = Stats::default();
+        assert!(stats.passed());
+        stats.malformed_sent = 3;
+        assert!(!stats.passed());
+        stats.malformed_warned = 3;
+        stats.violation(String::from("size without w"));
+        stats.violation(String::from("size without w"));
+        assert!(!stats.passed());
+        let report = stats.report(Duration::from_secs(1));
+        assert!(report.contains("Protocol violations: 2\n  2 × size without w\n"));
+        assert!(report.ends_with("FAIL"));
+    }
+}
@@ -0,0 +1,223 @@
+/************** Admin control channel **************
+ * Admins identify with `"?": "admin"` and the      *
+ * configured password, then send                   *
+ * `{"msg": "admin", "cmd": ...}` commands. These   *
+ * act on the admin's room, or on `"room"` if given. *
+ ***************************************************/
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use jsonic::json_item::JsonItem;
+
+use crate::moderation::{BanKey, Moderation};
+use crate::protocol::{escape, raw, CANVAS, PAINTER};
+use crate::room::Rooms;
+use crate::websocket::Message;
+use crate::{Client, ClientData};
+
+pub const ADMIN: &str = "admin";
+
+/// Run an admin command, returning the JSON reply for the admin
+pub fn command(
+    sent: &JsonItem,
+    room_name: &str,
+    cs: &mut HashMap<u64, Client>,
+    rooms: &mut Rooms,
+    moderation: &mut Moderation,
+) -> String {
+    let cmd = sent["cmd"].as_str().unwrap_or_default();
+    let id = sent["id"].as_i128().and_then(|id| u64::try_from(id).ok());
+    let Some(room) = rooms.get_mut(room_name) else {
+        return reply(cmd, Err(format!("There is no room called {}", room_name)));
+    };
+    let result = match (cmd, id) {
+        ("kick" | "ban" | "clear" | "freeze", Some(id))
+            if cs.get(&id).is_some_and(|client| client.room != room_name) =>
+        {
+            Err(format!("Client {} is not in {}", id, room_name))
+        }
+        ("list", _) => Ok(format!("\"clients\": [{}]", list(room_name, cs, moderation))),
+        ("rooms", _) => Ok(format!("\"rooms\": [{}]", list_rooms(cs, rooms))),
+        ("kick", Some(id)) => kick(id, cs, rooms).map(|_| String::new()),
+        ("ban", Some(id)) => {
+            let duration = sent["minutes"]
+                .as_f64()
+                .map(|minutes| Duration::from_secs_f64(minutes.max(0.0) * 60.0))
+                .or(moderation.policy.ban_duration);
+            // Behind a tunnel everyone shares an IP, so only ban it when asked to
+            let ip = sent["ip"].as_bool().unwrap_or(moderation.policy.ban_ips);
+            if let Some(client) = cs.get(&id) {
+                if ip {
+                    moderation.ban(BanKey::Ip(client.ip), duration);
+                }
+                if !client.name.is_empty() {
+                    moderation.ban(BanKey::Name(client.name.clone()), duration);
+                }
+            }
+            kick(id, cs, rooms).map(|_| String::new())
+        }
+        ("clear", Some(id)) => room
+            .buffer
+            .clear(id)
+            .then(String::new)
+            .ok_or_else(|| format!("No tile for client {}", id)),
+        ("freeze", Some(id)) => {
+            let frozen = sent["frozen"].as_bool().unwrap_or(true);
+            room.buffer
+                .set_frozen(id, frozen)
+                .then(String::new)
+                .ok_or_else(|| format!("No tile for client {}", id))
+        }
+        ("reset", _) => {
+            room.buffer.reset();
+            Ok(String::new())
+        }
+        ("poll-rate", _) => match sent["ms"].as_i128() {
+            Some(ms) if ms > 0 => {
+                room.poll_interval = Duration::from_millis(ms as u64);
+                Ok(String::new())
+            }
+            _ => Err(String::from("poll-rate expects a positive ms")),
+        },
+        ("announce", _) => {
+            let text = escape(sent["text"].as_str().unwrap_or_default());
+            let announcement = format!("{{\"msg\": \"announce\", \"text\": \"{}\"}}", text);
+            cs.values()
+                .filter(|client| client.room == room_name)
+                .for_each(|client| {
+                    client.responder.send(Message::Text(announcement.clone()));
+                });
+            Ok(String::new())
+        }
+        ("event", _) => match sent["kind"].as_str() {
+            Some(kind) if !kind.is_empty() => {
+                let painters = cs
+                    .iter()
+                    .filter(|(_, client)| {
+                        client.room == room_name && matches!(client.data, ClientData::Painter)
+                    })
+                    .map(|(id, client)| (*id, &client.responder));
+                let id = room.events.broadcast(kind, &event_fields(sent), painters);
+                Ok(format!("\"id\": {}", id))
+            }
+            _ => Err(String::from("event expects a kind")),
+        },
+        ("add-bot", _) => match sent["kind"].as_str().map(str::parse) {
+            Some(Ok(kind)) => rooms
+                .add_bot(room_name, kind)
+                .map(|id| format!("\"id\": {}", id)),
+            Some(Err(error)) => Err(error),
+            None => Err(String::from("add-bot expects a kind")),
+        },
+        ("remove-bot", Some(id)) => room
+            .remove_bot(id)
+            .then(String::new)
+            .ok_or_else(|| format!("No bot {}", id)),
+        ("bots", _) => {
+            let bots = room
+                .bots
+                .iter()
+                .map(|(id, bot)| {
+                    format!("{{\"id\": {}, \"kind\": \"{}\"}}", id, escape(&bot.kind.to_string()))
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            Ok(format!("\"bots\": [{}]", bots))
+        }
+        ("events", _) => Ok(format!("\"events\": [{}]", room.events.to_json())),
+        ("kick" | "ban" | "clear" | "freeze" | "remove-bot", None) => Err(format!("{} expects a client id", cmd)),
+        _ => Err(format!("Unknown admin command: {}", cmd)),
+    };
+    reply(cmd, result)
+}
+
+fn reply(cmd: &str, result: Result<String, String>) -> String {
+    let cmd = escape(cmd);
+    match result {
+        Ok(fields) if fields.is_empty() => {
+            format!("{{\"msg\": \"{ADMIN}\", \"cmd\": \"{cmd}\", \"ok\": true}}")
+        }
+        Ok(fields) => {
+            format!("{{\"msg\": \"{ADMIN}\", \"cmd\": \"{cmd}\", \"ok\": true, {fields}}}")
+        }
+        Err(error) => format!(
+            "{{\"msg\": \"{ADMIN}\", \"cmd\": \"{cmd}\", \"ok\": false, \"error\": \"{}\"}}",
+            escape(&error)
+        ),
+    }
+}
+
+/// Everything in an `event` command except what it takes for itself, to pass on to painters
+fn event_fields(sent: &JsonItem) -> String {
+    sent.entries()
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| !["msg", "cmd", "room", "kind", "id"].contains(&key.as_str()))
+        .filter_map(|(key, value)| Some(format!("\"{}\": {}", key.as_str(), raw(value)?)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn list(room_name: &str, cs: &HashMap<u64, Client>, moderation: &Moderation) -> String {
+    let mut ids: Vec<_> = cs
+        .iter()
+        .filter(|(_, client)| client.room == room_name)
+        .map(|(id, _)| id)
+        .collect();
+    ids.sort();
+    ids.into_iter()
+        .map(|id| {
+            let client = &cs[id];
+            let role = match client.data {
+                ClientData::Painter => PAINTER,
+                ClientData::Waiting => "waiting",
+                ClientData::Canvas => CANVAS,
+                ClientData::Admin => "admin",
+                ClientData::Unknown { .. } => "unknown",
+            };
+            format!(
+                "{{\"id\": {}, \"role\": \"{}\", \"room\": \"{}\", \"name\": \"{}\", \"url\": \"{}\", \"ip\": \"{}\", \"naughty\": {:.1}, \"idle\": {}, \"latency\": {}}}",
+                id,
+                role,
+                escape(&client.room),
+                escape(&client.name),
+                escape(&client.url),
+                client.ip,
+                client.naughty.current(&moderation.policy),
+                client.last_active.elapsed().as_secs(),
+                match client.data {
+                    ClientData::Painter => client.latency.to_json(),
+                    _ => String::from("null"),
+                }
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn list_rooms(cs: &HashMap<u64, Client>, rooms: &Rooms) -> String {
+    let mut names: Vec<_> = rooms.iter().collect();
+    names.sort_by_key(|(name, _)| *name);
+    names
+        .into_iter()
+        .map(|(name, room)| {
+            format!(
+                "{{\"room\": \"{}\", \"clients\": {}, \"tiles\": {}, \"poll_ms\": {}}}",
+                escape(name),
+                cs.values().filter(|client| &client.room == name).count(),
+                room.buffer.n_clients(),
+                room.poll_interval.as_millis()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn kick(id: u64, cs: &mut HashMap<u64, Client>, rooms: &mut Rooms) -> Result<(), String> {
+    let client = cs.remove(&id).ok_or_else(|| format!("No client {}", id))?;
+    println!("Admin kicked client #{}", id);
+    crate::reject(&client.responder, "Kicked by an admin");
+    rooms.remove(&client.room, id);
+    Ok(())
+}
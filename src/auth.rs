@@ -0,0 +1,84 @@
+/************** Authentication tokens **************
+ * When tokens are configured, painters and         *
+ * canvases must present one in the `?` handshake.  *
+ * A token may be bound to a painter name, in which *
+ * case only that name may be used with it, and     *
+ * that name may only be used with it.              *
+ ***************************************************/
+
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct Tokens {
+    /// token -> painter name it is bound to, if any
+    tokens: HashMap<String, Option<String>>,
+}
+
+impl Tokens {
+    pub fn add(&mut self, token: String, name: Option<String>) {
+        self.tokens.insert(token, name);
+    }
+
+    /// Parse lines of `TOKEN` or `TOKEN NAME`, ignoring blanks and `#` comments
+    pub fn add_file(&mut self, contents: &str) {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .for_each(|line| match line.split_once(char::is_whitespace) {
+                Some((token, name)) => {
+                    self.add(String::from(token), Some(String::from(name.trim())))
+                }
+                None => self.add(String::from(line), None),
+            });
+    }
+
+    pub fn required(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Check a token for a painter (with its name) or a canvas (without)
+    pub fn check(&self, token: Option<&str>, painter_name: Option<&str>) -> Result<(), String> {
+        if !self.required() {
+            return Ok(());
+        }
+        let token = token.ok_or_else(|| String::from("A token is required"))?;
+        let bound_to = self
+            .tokens
+            .get(token)
+            .ok_or_else(|| String::from("Unknown token"))?;
+        match (bound_to, painter_name) {
+            (Some(bound), Some(name)) if bound != name => {
+                Err(format!("This token is not valid for painter {}", name))
+            }
+            (None, Some(name)) if self.tokens.values().flatten().any(|bound| bound == name) => {
+                Err(format!("Painter name {} needs its own token", name))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_without_tokens() {
+        assert_eq!(Tokens::default().check(None, Some("anyone")), Ok(()));
+    }
+
+    #[test]
+    fn test_bound_tokens() {
+        let mut tokens = Tokens::default();
+        tokens.add_file("# teams\nshared\nred-secret Red Team\n\nblue-secret Blue");
+        assert_eq!(tokens.check(Some("shared"), None), Ok(()));
+        assert_eq!(tokens.check(Some("shared"), Some("Green")), Ok(()));
+        assert_eq!(tokens.check(Some("red-secret"), Some("Red Team")), Ok(()));
+        assert_eq!(tokens.check(Some("red-secret"), None), Ok(()));
+        assert!(tokens.check(Some("red-secret"), Some("Blue")).is_err());
+        assert!(tokens.check(Some("shared"), Some("Blue")).is_err());
+        assert!(tokens.check(Some("wrong"), None).is_err());
+        assert!(tokens.check(None, None).is_err());
+    }
+}
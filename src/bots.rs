@@ -0,0 +1,285 @@
+/************** Bot painters **************
+ * Painters built into the server, to fill *
+ * empty canvases for demos. They take a   *
+ * tile in a room's buffer like any other  *
+ * painter and draw a frame each time the  *
+ * room polls its painters.                *
+ *****************************************/
+
+use std::str::FromStr;
+
+use crate::buffer::{BUFFER_PIXELS, TILE_BYTES};
+
+const SIDE: usize = BUFFER_PIXELS;
+/// Font pixels are drawn as squares this wide
+const TEXT_SCALE: usize = 4;
+/// Life is re-seeded when it dies out or after this many generations
+const LIFE_GENERATIONS: u64 = 200;
+#[rustfmt::skip]
+const NEIGHBOURHOOD: [(isize, isize); 8] = [
+    (-1, -1), (0, -1), (1, -1),
+    (-1, 0),           (1, 0),
+    (-1, 1),  (0, 1),  (1, 1),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Plasma,
+    Gradient,
+    Life,
+    Text(String),
+    TestPattern,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    /// `plasma`, `gradient`, `life`, `test-pattern` or `text:MESSAGE`
+    fn from_str(kind: &str) -> Result<Kind, String> {
+        match kind {
+            "plasma" => Ok(Kind::Plasma),
+            "gradient" => Ok(Kind::Gradient),
+            "life" => Ok(Kind::Life),
+            "test-pattern" => Ok(Kind::TestPattern),
+            _ => match kind.strip_prefix("text:") {
+                Some(text) if !text.is_empty() => Ok(Kind::Text(String::from(text))),
+                _ => Err(format!(
+                    "{} is not a bot. Should be plasma, gradient, life, test-pattern or text:MESSAGE",
+                    kind
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::Plasma => write!(f, "plasma"),
+            Kind::Gradient => write!(f, "gradient"),
+            Kind::Life => write!(f, "life"),
+            Kind::Text(text) => write!(f, "text:{}", text),
+            Kind::TestPattern => write!(f, "test-pattern"),
+        }
+    }
+}
+
+pub struct Bot {
+    pub kind: Kind,
+    frame: u64,
+    cells: Vec<bool>,
+}
+
+impl Bot {
+    pub fn new(kind: Kind) -> Bot {
+        Bot {
+            kind,
+            frame: 0,
+            cells: Vec::new(),
+        }
+    }
+
+    /// Draw the next frame
+    pub fn render(&mut self) -> Vec<u8> {
+        let t = self.frame;
+        self.frame += 1;
+        let mut pixels = vec![0; TILE_BYTES];
+        if let Kind::Life = self.kind {
+            self.step_life();
+        }
+        for (i, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % SIDE, i / SIDE);
+            pixel.copy_from_slice(&match &self.kind {
+                Kind::Plasma => plasma(x, y, t),
+                Kind::Gradient => gradient(x, y, t),
+                Kind::Life if self.cells[i] => [80, 220, 120, 255],
+                Kind::Life => [10, 20, 30, 255],
+                Kind::Text(text) if lit(text, x, y, t) => [255, 255, 255, 255],
+                Kind::Text(_) => [20, 20, 60, 255],
+                Kind::TestPattern => test_pattern(x, y, t),
+            });
+        }
+        pixels
+    }
+
+    fn step_life(&mut self) {
+        let alive = self.cells.iter().filter(|&&cell| cell).count();
+        if alive < 5 || self.frame % LIFE_GENERATIONS == 1 {
+            self.cells = (0..SIDE * SIDE)
+                .map(|_| rand::random::<u8>() < 80)
+                .collect();
+            return;
+        }
+        let at = |x: usize, y: usize| self.cells[(y % SIDE) * SIDE + x % SIDE];
+        self.cells = (0..SIDE * SIDE)
+            .map(|i| {
+                // Wrap around the edges: SIDE - 1 steps forward is one step back
+                let (x, y) = (i % SIDE + SIDE, i / SIDE + SIDE);
+                let neighbours = NEIGHBOURHOOD
+                    .iter()
+                    .filter(|(dx, dy)| at((x as isize + dx) as usize, (y as isize + dy) as usize))
+                    .count();
+                matches!((self.cells[i], neighbours), (true, 2) | (_, 3))
+            })
+            .collect();
+    }
+}
+
+fn plasma(x: usize, y: usize, t: u64) -> [u8; 4] {
+    let (x, y, t) = (x as f64, y as f64, t as f64 * 0.3);
+    let v = (x / 5.0 + t).sin()
+        + (y / 4.0 - t).sin()
+        + ((x + y) / 6.0 + t).sin()
+        + ((x * x + y * y).sqrt() / 4.0 - t).sin();
+    let channel =
+        |phase: f64| ((v * std::f64::consts::PI / 2.0 + phase).sin() * 127.0 + 128.0) as u8;
+    [channel(0.0), channel(2.0), channel(4.0), 255]
+}
+
+fn gradient(x: usize, y: usize, t: u64) -> [u8; 4] {
+    let step = |n: usize| (n * 255 / (SIDE - 1)) as u8;
+    [step(x), step(y), (t * 16 % 256) as u8, 255]
+}
+
+fn test_pattern(x: usize, y: usize, t: u64) -> [u8; 4] {
+    const BARS: [[u8; 4]; 8] = [
+        [255, 255, 255, 255],
+        [255, 255, 0, 255],
+        [0, 255, 255, 255],
+        [0, 255, 0, 255],
+        [255, 0, 255, 255],
+        [255, 0, 0, 255],
+        [0, 0, 255, 255],
+        [0, 0, 0, 255],
+    ];
+    // A sweeping line shows the tile is live
+    if y == (t as usize) % SIDE {
+        return [128, 128, 128, 255];
+    }
+    BARS[x * BARS.len() / SIDE]
+}
+
+/// Whether a pixel is part of the scrolling text, which moves one font pixel per frame
+fn lit(text: &str, x: usize, y: usize, t: u64) -> bool {
+    let advance = 4 * TEXT_SCALE;
+    let top = (SIDE - 5 * TEXT_SCALE) / 2;
+    if y < top || y >= top + 5 * TEXT_SCALE {
+        return false;
+    }
+    // Start off the right edge and scroll until the text has gone, then repeat
+    let chars: Vec<char> = text.chars().collect();
+    let width = chars.len() * advance + SIDE;
+    let scrolled = (x + (t as usize * TEXT_SCALE) % width + width - SIDE) % width;
+    let (index, column) = (scrolled / advance, scrolled % advance / TEXT_SCALE);
+    let row = (y - top) / TEXT_SCALE;
+    column < 3
+        && chars
+            .get(index)
+            .is_some_and(|&c| glyph(c)[row] & (0b100 >> column) != 0)
+}
+
+/// A 3 × 5 glyph, one row per entry, most significant bit on the left
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        'A' => [2, 5, 7, 5, 5],
+        'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3],
+        'D' => [6, 5, 5, 5, 6],
+        'E' => [7, 4, 6, 4, 7],
+        'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3],
+        'H' => [5, 5, 7, 5, 5],
+        'I' => [7, 2, 2, 2, 7],
+        'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5],
+        'L' => [4, 4, 4, 4, 7],
+        'M' => [5, 7, 7, 5, 5],
+        'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2],
+        'P' => [6, 5, 6, 4, 4],
+        'Q' => [2, 5, 5, 6, 3],
+        'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6],
+        'T' => [7, 2, 2, 2, 2],
+        'U' => [5, 5, 5, 5, 7],
+        'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5],
+        'X' => [5, 5, 2, 5, 5],
+        'Y' => [5, 5, 2, 2, 2],
+        'Z' => [7, 1, 2, 4, 7],
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [6, 1, 2, 4, 7],
+        '3' => [6, 1, 2, 1, 6],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 6, 1, 6],
+        '6' => [3, 4, 7, 5, 7],
+        '7' => [7, 1, 2, 2, 2],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 6],
+        ' ' => [0, 0, 0, 0, 0],
+        '!' => [2, 2, 2, 0, 2],
+        '-' => [0, 0, 7, 0, 0],
+        '.' => [0, 0, 0, 0, 2],
+        ',' => [0, 0, 0, 2, 4],
+        ':' => [0, 2, 0, 2, 0],
+        '\'' => [2, 2, 0, 0, 0],
+        _ => [6, 1, 2, 0, 2],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinds() {
+        for kind in ["plasma", "gradient", "life", "test-pattern", "text:Hi!"] {
+            assert_eq!(kind.parse::<Kind>().unwrap().to_string(), kind);
+        }
+        assert!("text:".parse::<Kind>().is_err());
+        assert!("fractal".parse::<Kind>().is_err());
+    }
+
+    #[test]
+    fn test_every_bot_fills_its_tile() {
+        for kind in ["plasma", "gradient", "life", "test-pattern", "text:Hi!"] {
+            let mut bot = Bot::new(kind.parse().unwrap());
+            for _ in 0..3 {
+                let pixels = bot.render();
+                assert_eq!(pixels.len(), TILE_BYTES);
+                assert!(
+                    pixels.chunks_exact(4).all(|pixel| pixel[3] == 255),
+                    "{}",
+                    kind
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_life_blinker() {
+        let mut bot = Bot::new(Kind::Life);
+        bot.frame = 2;
+        bot.cells = vec![false; SIDE * SIDE];
+        for x in 10..13 {
+            bot.cells[10 * SIDE + x] = true;
+        }
+        // Keep it above the re-seeding threshold with a block far away
+        for (x, y) in [(30, 30), (31, 30), (30, 31), (31, 31)] {
+            bot.cells[y * SIDE + x] = true;
+        }
+        bot.step_life();
+        assert!(bot.cells[9 * SIDE + 11] && bot.cells[10 * SIDE + 11] && bot.cells[11 * SIDE + 11]);
+        assert!(!bot.cells[10 * SIDE + 10] && !bot.cells[10 * SIDE + 12]);
+    }
+
+    #[test]
+    fn test_text_scrolls_in_from_the_right() {
+        // At first the text is just off the right edge
+        assert!((0..SIDE).all(|x| (0..SIDE).all(|y| !lit("I", x, y, 0))));
+        // Once scrolled fully in, the top bar of the I is lit
+        let t = (SIDE / TEXT_SCALE) as u64;
+        assert!(lit("I", 0, (SIDE - 5 * TEXT_SCALE) / 2, t));
+    }
+}
@@ -15,10 +15,12 @@ pub enum UpdateError {
     Client(String),
 }
 
-pub const BUFFER_PIXELS: usize = 40;
+pub use crate::protocol::TILE_BYTES;
+use crate::protocol::PIXEL_SIZE;
+
+pub const BUFFER_PIXELS: usize = crate::protocol::TILE_PIXELS;
 pub const MAX_CLIENTS: usize = 64;
-const CLIENT_PIXELS: usize = 40 * 40;
-const PIXEL_SIZE: usize = 4;
+const CLIENT_PIXELS: usize = BUFFER_PIXELS * BUFFER_PIXELS;
 
 
 #[rustfmt::skip]
@@ -44,6 +46,30 @@ static GRID_POSITION: [(usize, usize); 64] = [
 struct Client {
     id: u64,
     buffer: [u8; CLIENT_PIXELS * PIXEL_SIZE],
+    stale: bool,
+    frozen: bool,
+    /// Whether the client tags its frames with ticks
+    synced: bool,
+    /// A tagged frame waiting for the rest of its tick
+    pending: Option<(u64, Vec<u8>)>,
+    /// The tick of the last tagged frame shown
+    shown_tick: u64,
+}
+
+impl Client {
+    /// The pixels to draw for this client: greyed out when stale
+    fn rendered(&self) -> [u8; CLIENT_PIXELS * PIXEL_SIZE] {
+        if !self.stale {
+            return self.buffer;
+        }
+        let mut greyed = self.buffer;
+        greyed.chunks_exact_mut(PIXEL_SIZE).for_each(|pixel| {
+            let luma = (299 * pixel[0] as u32 + 587 * pixel[1] as u32 + 114 * pixel[2] as u32) / 1000;
+            let grey = ((luma + 128) / 2) as u8;
+            pixel.copy_from_slice(&[grey, grey, grey, 255]);
+        });
+        greyed
+    }
 }
 
 impl std::fmt::Debug for Client {
@@ -55,6 +81,10 @@ impl std::fmt::Debug for Client {
 pub struct Buffer {
     clients: Vec<Client>,
     pixels: Vec<u8>,
+    /// Bumped whenever the pixels change, so derived images know when to refresh
+    version: u64,
+    /// Bumped whenever clients come, go or change ids, moving tiles around
+    layout: u64,
 }
 
 impl<'a> From<&'a Buffer> for &'a Vec<u8> {
@@ -75,12 +105,14 @@ impl Buffer {
         Buffer {
             clients: Vec::new(),
             pixels,
+            version: 0,
+            layout: 0,
         }
     }
 
     pub fn insert(&mut self, id: u64) -> Result<(), String> {
         if self.clients.iter().any(|client| client.id == id) {
-            return Err(format!("Client already in image buffer"));
+            return Err(String::from("Client already in image buffer"));
         }
 
         if self.n_clients() >= MAX_CLIENTS {
@@ -92,50 +124,41 @@ impl Buffer {
         let client = Client {
             id,
             buffer: [0; CLIENT_PIXELS * PIXEL_SIZE],
+            stale: false,
+            frozen: false,
+            synced: false,
+            pending: None,
+            shown_tick: 0,
         };
         self.clients.push(client);
+        self.version += 1;
+        self.layout += 1;
 
-        let post_dim = self.dim();
-        if pre_dim < post_dim {
-            // Need to re-size and re-render
-            self.pixels.resize(post_dim * post_dim * PIXEL_SIZE, 0);
-            self.full_render();
+        if pre_dim < self.dim() {
+            self.redraw();
         }
         Ok(())
     }
 
+    /// Take a client's tile off the canvas. Later tiles move up a slot, and the canvas
+    /// shrinks if it can.
     pub fn remove(&mut self, id: u64) {
         if let Some(i) = self.clients.iter().position(|c| c.id == id) {
             self.clients.remove(i);
+            self.version += 1;
+            self.layout += 1;
+            self.redraw();
         }
     }
 
     pub fn update(&mut self, id: u64, data: Vec<u8>) -> Result<(), UpdateError> {
-        let size_delta = (CLIENT_PIXELS * PIXEL_SIZE).cmp(&data.len());
-        if size_delta == std::cmp::Ordering::Less {
-            Err(UpdateError::Client(String::from(
-                "Warning: data is larger than expected",
-            )))
-        } else if size_delta == std::cmp::Ordering::Greater {
-            Err(UpdateError::Client(String::from(
-                "Warning: data is smaller than expected",
-            )))
-        } else {
-            Ok(())
-        }?;
+        check_size(&data)?;
 
         if let Some(i) = self.clients.iter().position(|c| c.id == id) {
-            self.clients[i]
-                .buffer
-                .copy_from_slice(&data[0..(CLIENT_PIXELS * PIXEL_SIZE)]);
-            if let Some((x, y)) = coordinate_of(i + 1) {
-                self.blit(x * BUFFER_PIXELS, y * BUFFER_PIXELS, self.clients[i].buffer);
-            } else {
-                return Err(UpdateError::Server(format!(
-                    "Not a valid coordinate: {}",
-                    i + 1
-                )));
+            if self.clients[i].frozen {
+                return Ok(());
             }
+            self.show(i, &data)?;
         } else {
             return Err(UpdateError::Server(String::from(
                 "Error: could not find the client to update pixels.",
@@ -144,6 +167,179 @@ impl Buffer {
         Ok(())
     }
 
+    /// Hold a frame tagged with a tick until every synced client has sent one for
+    /// that tick, then show them all at once
+    pub fn update_tick(&mut self, id: u64, tick: u64, data: Vec<u8>) -> Result<(), UpdateError> {
+        check_size(&data)?;
+
+        let Some(client) = self.clients.iter_mut().find(|c| c.id == id) else {
+            return Err(UpdateError::Server(String::from(
+                "Error: could not find the client to update pixels.",
+            )));
+        };
+        if client.frozen {
+            return Ok(());
+        }
+        client.synced = true;
+        client.pending = Some((tick, data));
+
+        let complete = self
+            .clients
+            .iter()
+            .filter(|c| c.synced && !c.stale && !c.frozen)
+            .all(|c| match &c.pending {
+                Some((t, _)) => *t == tick,
+                None => c.shown_tick >= tick,
+            });
+        if complete {
+            self.show_tick(tick)?;
+        }
+        Ok(())
+    }
+
+    /// Show the frames held for this tick or earlier, e.g. when the tick has run out of time
+    pub fn show_tick(&mut self, tick: u64) -> Result<(), UpdateError> {
+        for i in 0..self.clients.len() {
+            if self.clients[i].pending.as_ref().is_some_and(|(t, _)| *t <= tick) {
+                if let Some((t, data)) = self.clients[i].pending.take() {
+                    self.clients[i].shown_tick = t;
+                    self.show(i, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn show(&mut self, i: usize, data: &[u8]) -> Result<(), UpdateError> {
+        self.clients[i]
+            .buffer
+            .copy_from_slice(&data[0..(CLIENT_PIXELS * PIXEL_SIZE)]);
+        self.clients[i].stale = false;
+        if let Some((x, y)) = coordinate_of(i + 1) {
+            self.blit(x * BUFFER_PIXELS, y * BUFFER_PIXELS, self.clients[i].buffer);
+            Ok(())
+        } else {
+            Err(UpdateError::Server(format!(
+                "Not a valid coordinate: {}",
+                i + 1
+            )))
+        }
+    }
+
+    /// Each client's id and pixels, in slot order
+    pub fn tiles(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.clients.iter().map(|c| (c.id, &c.buffer[..]))
+    }
+
+    /// Re-create a client's tile from saved pixels, greyed out until it sends fresh ones
+    pub fn restore(&mut self, id: u64, pixels: &[u8]) -> Result<(), String> {
+        if pixels.len() != TILE_BYTES {
+            return Err(format!("Saved tile for {} is the wrong size", id));
+        }
+        self.insert(id)?;
+        let i = self.clients.len() - 1;
+        self.clients[i].buffer.copy_from_slice(pixels);
+        self.clients[i].stale = true;
+        if let Some((x, y)) = coordinate_of(i + 1) {
+            self.blit(x * BUFFER_PIXELS, y * BUFFER_PIXELS, self.clients[i].rendered());
+        }
+        Ok(())
+    }
+
+    /// Hand a client's slot and pixels over to a new id, e.g. when it reconnects
+    pub fn rekey(&mut self, from: u64, to: u64) -> bool {
+        if self.clients.iter().any(|c| c.id == to) {
+            return false;
+        }
+        if let Some(client) = self.clients.iter_mut().find(|c| c.id == from) {
+            client.id = to;
+            self.layout += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Grey out (or restore) a client's tile, e.g. when it stops sending pixels
+    pub fn set_stale(&mut self, id: u64, stale: bool) {
+        if let Some(i) = self.clients.iter().position(|c| c.id == id) {
+            if self.clients[i].stale == stale {
+                return;
+            }
+            self.clients[i].stale = stale;
+            if let Some((x, y)) = coordinate_of(i + 1) {
+                self.blit(x * BUFFER_PIXELS, y * BUFFER_PIXELS, self.clients[i].rendered());
+            }
+        }
+    }
+
+    /// Ignore (or resume) pixel updates from a client
+    pub fn set_frozen(&mut self, id: u64, frozen: bool) -> bool {
+        if let Some(client) = self.clients.iter_mut().find(|c| c.id == id) {
+            client.frozen = frozen;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Blank a client's tile
+    pub fn clear(&mut self, id: u64) -> bool {
+        if let Some(i) = self.clients.iter().position(|c| c.id == id) {
+            self.clients[i].buffer = [0; CLIENT_PIXELS * PIXEL_SIZE];
+            if let Some((x, y)) = coordinate_of(i + 1) {
+                self.blit(x * BUFFER_PIXELS, y * BUFFER_PIXELS, self.clients[i].rendered());
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Blank every tile, keeping the clients in place
+    pub fn reset(&mut self) {
+        self.clients
+            .iter_mut()
+            .for_each(|c| c.buffer = [0; CLIENT_PIXELS * PIXEL_SIZE]);
+        self.redraw();
+    }
+
+    /// The column and row of a client's tile
+    pub fn position(&self, id: u64) -> Option<(usize, usize)> {
+        let i = self.clients.iter().position(|c| c.id == id)?;
+        coordinate_of(i + 1)
+    }
+
+    /// The pixels bordering a client's tile, from its north, east, south then west
+    /// neighbour: the row or column touching the tile, or with `whole` the neighbour's
+    /// entire tile. Missing neighbours are transparent.
+    pub fn neighbours(&self, id: u64, whole: bool) -> Option<Vec<u8>> {
+        let (x, y) = self.position(id)?;
+        let occupied = &GRID_POSITION[..self.clients.len()];
+        let row = BUFFER_PIXELS * PIXEL_SIZE;
+        let mut out = Vec::new();
+        for (dx, dy) in [(0, -1), (1, 0), (0, 1), (-1, 0)] {
+            let tile = x
+                .checked_add_signed(dx)
+                .zip(y.checked_add_signed(dy))
+                .and_then(|at| occupied.iter().position(|&p| p == at))
+                .map(|i| self.clients[i].rendered())
+                .unwrap_or([0; CLIENT_PIXELS * PIXEL_SIZE]);
+            match (whole, dx, dy) {
+                (true, _, _) => out.extend(tile),
+                (false, 0, -1) => out.extend(&tile[tile.len() - row..]),
+                (false, 0, _) => out.extend(&tile[..row]),
+                (false, 1, _) => tile
+                    .chunks_exact(row)
+                    .for_each(|line| out.extend(&line[..PIXEL_SIZE])),
+                (false, _, _) => tile
+                    .chunks_exact(row)
+                    .for_each(|line| out.extend(&line[row - PIXEL_SIZE..])),
+            }
+        }
+        Some(out)
+    }
+
     pub fn dim(&self) -> usize {
         (self.clients.len() as f32).sqrt().ceil() as usize * BUFFER_PIXELS
     }
@@ -152,10 +348,19 @@ impl Buffer {
         self.clients.len()
     }
 
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn layout(&self) -> u64 {
+        self.layout
+    }
+
     fn blit(&mut self, x: usize, y: usize, source: [u8; CLIENT_PIXELS * PIXEL_SIZE]) {
         let copy_width = BUFFER_PIXELS * PIXEL_SIZE;
         let buffer_width = self.dim() * PIXEL_SIZE;
         let start = y * buffer_width + x * PIXEL_SIZE;
+        self.version += 1;
         (0..BUFFER_PIXELS).for_each(|y_off| {
             let dst_from = start + y_off * buffer_width;
             let dst_to = dst_from + copy_width;
@@ -165,6 +370,15 @@ impl Buffer {
         })
     }
 
+    /// Blank the canvas at its current size and draw every tile in its slot
+    fn redraw(&mut self) {
+        let dim = self.dim();
+        self.pixels.clear();
+        self.pixels.resize(dim * dim * PIXEL_SIZE, 0);
+        self.full_render();
+        self.version += 1;
+    }
+
     fn full_render(&mut self) {
         // The trick here is to position the client buffers correctly
         let render_data: Vec<_> = self
@@ -173,7 +387,7 @@ impl Buffer {
             .enumerate()
             .filter_map(|(i, c)| {
                 if let Some((x, y)) = coordinate_of(i + 1) {
-                    Some((x * BUFFER_PIXELS, y * BUFFER_PIXELS, c.buffer))
+                    Some((x * BUFFER_PIXELS, y * BUFFER_PIXELS, c.rendered()))
                 } else {
                     None
                 }
@@ -185,6 +399,21 @@ impl Buffer {
     }
 }
 
+fn check_size(data: &[u8]) -> Result<(), UpdateError> {
+    let size_delta = (CLIENT_PIXELS * PIXEL_SIZE).cmp(&data.len());
+    if size_delta == std::cmp::Ordering::Less {
+        Err(UpdateError::Client(String::from(
+            "Warning: data is larger than expected",
+        )))
+    } else if size_delta == std::cmp::Ordering::Greater {
+        Err(UpdateError::Client(String::from(
+            "Warning: data is smaller than expected",
+        )))
+    } else {
+        Ok(())
+    }
+}
+
 fn coordinate_of(i: usize) -> Option<(usize, usize)> {
     if i > 0 && i <= 64 {
         Some(GRID_POSITION[i - 1])
@@ -271,4 +500,120 @@ mod tests {
 
         assert_eq!(<&Vec::<u8>>::from(&buf), &expected);
     }
+
+    #[test]
+    fn test_frozen_and_cleared_tile() {
+        let mut buf = Buffer::new();
+        assert_eq!(buf.insert(0), Ok(()));
+        assert_eq!(buf.update(0, vec![255; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+
+        assert!(buf.set_frozen(0, true));
+        assert_eq!(buf.update(0, vec![100; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        assert_eq!(<&Vec::<u8>>::from(&buf), &vec![255; CLIENT_PIXELS * PIXEL_SIZE]);
+
+        assert!(buf.clear(0));
+        assert_eq!(<&Vec::<u8>>::from(&buf), &vec![0; CLIENT_PIXELS * PIXEL_SIZE]);
+        assert!(!buf.clear(1));
+    }
+
+    #[test]
+    fn test_tick_frames_shown_together() {
+        let mut buf = Buffer::new();
+        assert_eq!(buf.insert(0), Ok(()));
+        assert_eq!(buf.insert(1), Ok(()));
+        assert_eq!(buf.update_tick(0, 1, vec![10; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        assert_eq!(buf.update_tick(1, 1, vec![20; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        let pixels = <&Vec::<u8>>::from(&buf);
+        assert_eq!((pixels[0], pixels[BUFFER_PIXELS * PIXEL_SIZE]), (10, 20));
+
+        // Client 1 is late for tick 2, so client 0's frame waits for it
+        assert_eq!(buf.update_tick(0, 2, vec![30; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        assert_eq!(<&Vec::<u8>>::from(&buf)[0], 10);
+        assert_eq!(buf.show_tick(2), Ok(()));
+        assert_eq!(<&Vec::<u8>>::from(&buf)[0], 30);
+    }
+
+    #[test]
+    fn test_neighbours() {
+        let mut buf = Buffer::new();
+        for id in 0..3 {
+            assert_eq!(buf.insert(id), Ok(()));
+            assert_eq!(buf.update(id, vec![id as u8 + 1; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        }
+        // 0 1
+        // 2
+        assert_eq!(buf.position(2), Some((0, 1)));
+        let edge = BUFFER_PIXELS * PIXEL_SIZE;
+        let edges = buf.neighbours(0, false).unwrap();
+        assert_eq!(edges.len(), 4 * edge);
+        assert_eq!(edges[..edge], vec![0; edge]);
+        assert_eq!(edges[edge..2 * edge], vec![2; edge]);
+        assert_eq!(edges[2 * edge..3 * edge], vec![3; edge]);
+        assert_eq!(edges[3 * edge..], vec![0; edge]);
+
+        let tiles = buf.neighbours(2, true).unwrap();
+        assert_eq!(tiles.len(), 4 * CLIENT_PIXELS * PIXEL_SIZE);
+        assert_eq!(tiles[0], 1);
+        assert_eq!(tiles[CLIENT_PIXELS * PIXEL_SIZE], 0);
+        assert!(buf.neighbours(9, false).is_none());
+    }
+
+    #[test]
+    fn test_stale_tile() {
+        let mut buf = Buffer::new();
+        assert_eq!(buf.insert(0), Ok(()));
+        assert_eq!(buf.update(0, vec![0; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+
+        buf.set_stale(0, true);
+        let greyed: Vec<u8> = [64, 64, 64, 255]
+            .iter()
+            .cycle()
+            .take(CLIENT_PIXELS * PIXEL_SIZE)
+            .cloned()
+            .collect();
+        assert_eq!(<&Vec::<u8>>::from(&buf), &greyed);
+
+        // fresh pixels restore the tile
+        assert_eq!(buf.update(0, vec![255; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        assert_eq!(<&Vec::<u8>>::from(&buf), &vec![255; CLIENT_PIXELS * PIXEL_SIZE]);
+    }
+
+    #[test]
+    fn test_remove_redraws_the_canvas() {
+        let mut buf = Buffer::new();
+        for id in 0..5 {
+            assert_eq!(buf.insert(id), Ok(()));
+            assert_eq!(buf.update(id, vec![id as u8 + 1; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        }
+        assert_eq!(buf.dim(), 3 * BUFFER_PIXELS);
+        let at = |buf: &Buffer, x: usize, y: usize| {
+            <&Vec<u8>>::from(buf)[(y * buf.dim() + x) * PIXEL_SIZE]
+        };
+
+        // 0 1 4      0 2
+        // 2 3    ->  3 4
+        buf.remove(1);
+        assert_eq!(buf.dim(), 2 * BUFFER_PIXELS);
+        assert_eq!(<&Vec<u8>>::from(&buf).len(), 4 * CLIENT_PIXELS * PIXEL_SIZE);
+        assert_eq!(at(&buf, 0, 0), 1);
+        assert_eq!(at(&buf, BUFFER_PIXELS, 0), 3);
+        assert_eq!(at(&buf, 0, BUFFER_PIXELS), 4);
+        assert_eq!(at(&buf, BUFFER_PIXELS, BUFFER_PIXELS), 5);
+
+        // The vacated slot is blank
+        buf.remove(4);
+        assert_eq!(at(&buf, BUFFER_PIXELS, BUFFER_PIXELS), 0);
+    }
+
+    #[test]
+    fn test_reset_blanks_everything() {
+        let mut buf = Buffer::new();
+        for id in 0..2 {
+            assert_eq!(buf.insert(id), Ok(()));
+            assert_eq!(buf.update(id, vec![13; CLIENT_PIXELS * PIXEL_SIZE]), Ok(()));
+        }
+        buf.remove(1);
+        buf.reset();
+        assert!(<&Vec<u8>>::from(&buf).iter().all(|&channel| channel == 0));
+    }
 }
@@ -0,0 +1,277 @@
+/************** Server configuration **************
+ * Parsed from command line flags: --flag value    *
+ **************************************************/
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::auth::Tokens;
+use crate::bots::Kind;
+use crate::moderation::{BanKey, Policy};
+use crate::tls::Tls;
+
+pub struct Config {
+    pub port: u16,
+    pub poll_interval: Duration,
+    /// Unanswered `p` polls before a painter's tile is greyed out (0 = never)
+    pub stale_after_polls: u32,
+    /// Unanswered `p` polls before a painter is evicted (0 = never)
+    pub evict_after_polls: u32,
+    /// How long a client has to answer `?` before it is asked again
+    pub handshake_timeout: Duration,
+    /// How many times `?` is re-sent before an unidentified client is closed
+    pub handshake_retries: u32,
+    /// Maximum number of connections which have not yet answered `?` (0 = unlimited)
+    pub max_unidentified: usize,
+    /// Binary pixel uploads allowed per second per client (0 = unlimited)
+    pub upload_rate: f64,
+    /// `p` requests allowed per second per client (0 = unlimited)
+    pub pixel_request_rate: f64,
+    /// Text messages of any kind allowed per second per client (0 = unlimited)
+    pub message_rate: f64,
+    /// Frames per second push painters may send, and the fastest poll painters may ask for (0 = unlimited)
+    pub max_fps: f64,
+    pub policy: Policy,
+    /// IPs and painter names banned from the start
+    pub bans: Vec<BanKey>,
+    /// Password for the admin role (None = no admins)
+    pub admin_password: Option<String>,
+    /// Tokens painters and canvases must present (none = open to all)
+    pub tokens: Tokens,
+    /// Serve wss:// instead of ws://
+    pub tls: Option<Tls>,
+    /// Origins canvases may connect from (empty = any)
+    pub allowed_origins: Vec<String>,
+    /// Connections allowed from one IP address (0 = unlimited)
+    pub max_connections_per_ip: usize,
+    /// Connections of any kind allowed at once (0 = unlimited)
+    pub max_connections: usize,
+    /// How long a disconnected painter's tile is kept for it to resume (0 = no resuming)
+    pub resume_grace: Duration,
+    /// How long painters in a full room paint before giving someone waiting a go
+    pub turn: Option<Duration>,
+    /// Where to save the canvas periodically
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: Duration,
+    /// Load the snapshot at startup
+    pub restore: bool,
+    /// How long restored tiles wait for their painters to come back
+    pub restore_grace: Duration,
+    /// Rooms which always exist, besides the default one
+    pub rooms: Vec<String>,
+    /// Whether the `?` handshake may create new rooms
+    pub on_demand_rooms: bool,
+    pub max_rooms: usize,
+    /// Built-in painters to start with, and their rooms
+    pub bots: Vec<(Kind, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 8080,
+            poll_interval: Duration::from_secs(1),
+            stale_after_polls: 5,
+            evict_after_polls: 30,
+            handshake_timeout: Duration::from_secs(5),
+            handshake_retries: 2,
+            max_unidentified: 32,
+            upload_rate: 5.0,
+            pixel_request_rate: 5.0,
+            message_rate: 10.0,
+            max_fps: 5.0,
+            policy: Policy::default(),
+            bans: Vec::new(),
+            admin_password: None,
+            tokens: Tokens::default(),
+            tls: None,
+            allowed_origins: Vec::new(),
+            max_connections_per_ip: 0,
+            max_connections: 256,
+            resume_grace: Duration::from_secs(30),
+            turn: None,
+            snapshot: None,
+            snapshot_interval: Duration::from_secs(10),
+            restore: false,
+            restore_grace: Duration::from_secs(10 * 60),
+            rooms: Vec::new(),
+            on_demand_rooms: true,
+            max_rooms: 16,
+            bots: Vec::new(),
+        }
+    }
+}
+
+pub const USAGE: &str = "Usage: jeeves [OPTIONS]
+
+Options:
+  --port N                 Port to listen on (default 8080, 0 = any free port)
+  --poll-interval-ms N     Milliseconds between pixel polls (default 1000)
+  --stale-after-polls N    Grey out a painter's tile after N unanswered polls (default 5, 0 = never)
+  --evict-after-polls N    Evict a painter after N unanswered polls (default 30, 0 = never)
+  --handshake-timeout-ms N Milliseconds a client has to answer ? (default 5000)
+  --handshake-retries N    Times to re-ask ? before closing the connection (default 2)
+  --max-unidentified N     Maximum concurrent clients yet to answer ? (default 32, 0 = unlimited)
+  --upload-rate N          Pixel uploads per second per client (default 5, 0 = unlimited)
+  --pixel-request-rate N   p requests per second per client (default 5, 0 = unlimited)
+  --message-rate N         Text messages per second per client (default 10, 0 = unlimited)
+  --max-fps N              Frames per second push painters may send (default 5, 0 = unlimited;
+                           at most --upload-rate)
+  --cooldown-at N          Naughty score at which uploads are ignored for a while (default 25)
+  --cooldown-ms N          How long a cooldown lasts (default 10000)
+  --warning-at N           Naughty score at which the FINAL WARNING is sent (default 50)
+  --kick-at N              Naughty score past which a client is kicked (default 50)
+  --naughty-decay N        Naughty points forgiven per second (default 0.1)
+  --kicks-before-ban N     Kicks before a client's painter name is banned (default 3, 0 = never)
+  --ban-ips                Also ban the IPs of repeatedly kicked clients (not behind a tunnel)
+  --ban-minutes N          How long bans last (default 10, 0 = until restart)
+  --ban-ip IP              Ban an IP address (repeatable)
+  --ban-name NAME          Ban a painter name (repeatable)
+  --admin-password SECRET  Allow admin clients who present SECRET
+  --token TOKEN            Require painters and canvases to present a token; TOKEN is one (repeatable)
+  --team-token NAME=TOKEN  A token only valid for the painter called NAME (repeatable)
+  --tokens-file PATH       Read tokens from PATH, one `TOKEN` or `TOKEN NAME` per line
+  --tls-cert PATH          Serve wss:// using the PEM certificate chain at PATH (needs --tls-key)
+  --tls-key PATH           PEM private key for --tls-cert
+  --tls-self-signed HOST   Serve wss:// with a self-signed certificate for HOST (repeatable)
+  --allow-origin ORIGIN    Only accept canvases from this HTTP Origin (repeatable, default any)
+  --max-connections-per-ip N  Connections allowed from one IP (default 0 = unlimited)
+  --max-connections N      Connections allowed in total (default 256, 0 = unlimited)
+  --resume-grace-ms N      Keep a disconnected painter's tile for N ms so it can resume (default 30000, 0 = never)
+  --turn-secs N            In full rooms, painters yield to those waiting after N seconds (default 0 = never)
+  --snapshot PATH          Save the canvas to PATH periodically
+  --snapshot-interval-ms N Milliseconds between snapshots (default 10000)
+  --restore                Load the --snapshot file at startup; tiles are greyed until their painters return
+  --restore-grace-ms N     How long restored tiles wait for their painters (default 600000)
+  --room NAME              Declare a room which always exists (repeatable)
+  --fixed-rooms            Only allow declared rooms, instead of creating them on demand
+  --max-rooms N            Maximum number of rooms (default 16)
+  --bot KIND[@ROOM]        Start a built-in painter: plasma, gradient, life, test-pattern or text:MESSAGE (repeatable)
+  --help                   Print this message";
+
+impl Config {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut tls_cert: Option<String> = None;
+        let mut tls_key: Option<String> = None;
+        let mut self_signed: Vec<String> = Vec::new();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--help" | "-h" => return Err(String::from(USAGE)),
+                "--port" => config.port = value(&flag, args.next())?,
+                "--poll-interval-ms" => {
+                    config.poll_interval = Duration::from_millis(value(&flag, args.next())?)
+                }
+                "--stale-after-polls" => config.stale_after_polls = value(&flag, args.next())?,
+                "--evict-after-polls" => config.evict_after_polls = value(&flag, args.next())?,
+                "--handshake-timeout-ms" => {
+                    config.handshake_timeout = Duration::from_millis(value(&flag, args.next())?)
+                }
+                "--handshake-retries" => config.handshake_retries = value(&flag, args.next())?,
+                "--max-unidentified" => config.max_unidentified = value(&flag, args.next())?,
+                "--upload-rate" => config.upload_rate = value(&flag, args.next())?,
+                "--pixel-request-rate" => config.pixel_request_rate = value(&flag, args.next())?,
+                "--message-rate" => config.message_rate = value(&flag, args.next())?,
+                "--max-fps" => config.max_fps = value(&flag, args.next())?,
+                "--cooldown-at" => config.policy.cooldown_at = value(&flag, args.next())?,
+                "--cooldown-ms" => {
+                    config.policy.cooldown = Duration::from_millis(value(&flag, args.next())?)
+                }
+                "--warning-at" => config.policy.warning_at = value(&flag, args.next())?,
+                "--kick-at" => config.policy.kick_at = value(&flag, args.next())?,
+                "--naughty-decay" => config.policy.decay_per_sec = value(&flag, args.next())?,
+                "--kicks-before-ban" => config.policy.kicks_before_ban = value(&flag, args.next())?,
+                "--ban-ips" => config.policy.ban_ips = true,
+                "--ban-minutes" => {
+                    let minutes: u64 = value(&flag, args.next())?;
+                    config.policy.ban_duration =
+                        (minutes > 0).then(|| Duration::from_secs(minutes * 60));
+                }
+                "--ban-ip" => config.bans.push(BanKey::Ip(value(&flag, args.next())?)),
+                "--ban-name" => config.bans.push(BanKey::Name(value(&flag, args.next())?)),
+                "--admin-password" => config.admin_password = Some(value(&flag, args.next())?),
+                "--token" => config.tokens.add(value(&flag, args.next())?, None),
+                "--team-token" => {
+                    let team: String = value(&flag, args.next())?;
+                    let (name, token) = team
+                        .split_once('=')
+                        .ok_or_else(|| format!("{} expects NAME=TOKEN", flag))?;
+                    config
+                        .tokens
+                        .add(String::from(token), Some(String::from(name)));
+                }
+                "--tokens-file" => {
+                    let path: String = value(&flag, args.next())?;
+                    let contents = std::fs::read_to_string(&path)
+                        .map_err(|e| format!("Cannot read tokens file {}: {}", path, e))?;
+                    config.tokens.add_file(&contents);
+                }
+                "--tls-cert" => tls_cert = Some(value(&flag, args.next())?),
+                "--tls-key" => tls_key = Some(value(&flag, args.next())?),
+                "--tls-self-signed" => self_signed.push(value(&flag, args.next())?),
+                "--allow-origin" => config.allowed_origins.push(value(&flag, args.next())?),
+                "--max-connections-per-ip" => {
+                    config.max_connections_per_ip = value(&flag, args.next())?
+                }
+                "--max-connections" => config.max_connections = value(&flag, args.next())?,
+                "--resume-grace-ms" => {
+                    config.resume_grace = Duration::from_millis(value(&flag, args.next())?)
+                }
+                "--snapshot" => config.snapshot = Some(value(&flag, args.next())?),
+                "--snapshot-interval-ms" => {
+                    config.snapshot_interval = Duration::from_millis(value(&flag, args.next())?)
+                }
+                "--restore" => config.restore = true,
+                "--restore-grace-ms" => {
+                    config.restore_grace = Duration::from_millis(value(&flag, args.next())?)
+                }
+                "--room" => config.rooms.push(value(&flag, args.next())?),
+                "--fixed-rooms" => config.on_demand_rooms = false,
+                "--max-rooms" => config.max_rooms = value(&flag, args.next())?,
+                "--turn-secs" => {
+                    let secs: u64 = value(&flag, args.next())?;
+                    config.turn = (secs > 0).then(|| Duration::from_secs(secs));
+                }
+                "--bot" => {
+                    let bot: String = value(&flag, args.next())?;
+                    let (kind, room) = match bot.rsplit_once('@') {
+                        Some((kind, room)) => (kind, room),
+                        None => (bot.as_str(), crate::room::DEFAULT_ROOM),
+                    };
+                    config.bots.push((kind.parse()?, String::from(room)));
+                }
+                _ => return Err(format!("Unknown option: {}\n\n{}", flag, USAGE)),
+            }
+        }
+        config.tls = match (tls_cert, tls_key, self_signed.is_empty()) {
+            (Some(cert), Some(key), true) => Some(Tls::Files { cert, key }),
+            (None, None, false) => Some(Tls::SelfSigned(self_signed)),
+            (None, None, true) => None,
+            (_, _, false) => {
+                return Err(String::from(
+                    "--tls-self-signed cannot be used with --tls-cert or --tls-key",
+                ))
+            }
+            _ => return Err(String::from("--tls-cert and --tls-key must be used together")),
+        };
+        // Push painters sending as fast as they're allowed would otherwise be punished for it
+        if config.upload_rate > 0.0
+            && (config.max_fps <= 0.0 || config.max_fps > config.upload_rate)
+        {
+            return Err(String::from(
+                "--max-fps cannot be unlimited or above --upload-rate; raise --upload-rate too",
+            ));
+        }
+        if config.restore && config.snapshot.is_none() {
+            return Err(String::from("--restore needs --snapshot PATH"));
+        }
+        Ok(config)
+    }
+}
+
+fn value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} expects a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("{} got an invalid value: {}", flag, value))
+}
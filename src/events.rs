@@ -0,0 +1,150 @@
+/************** Broadcast events **************
+ * Themed events sent to every painter in a    *
+ * room, e.g. a palette or a countdown, as     *
+ * `{"msg": "event", "id": ..., "kind": ...}`. *
+ * Painters reply `{"msg": "ack", "id": ...}`  *
+ * and the room remembers who has answered.    *
+ *********************************************/
+
+use std::collections::{BTreeSet, VecDeque};
+use std::time::Instant;
+
+pub use crate::protocol::{ACK, EVENT};
+use crate::protocol::escape;
+use crate::websocket::{Message, Responder};
+
+/// How many past events each room keeps track of
+const KEEP_EVENTS: usize = 16;
+
+struct Event {
+    id: u64,
+    kind: String,
+    sent_at: Instant,
+    waiting: BTreeSet<u64>,
+    acked: BTreeSet<u64>,
+}
+
+#[derive(Default)]
+pub struct Events {
+    next_id: u64,
+    recent: VecDeque<Event>,
+}
+
+impl Events {
+    /// Send an event to these painters, returning its id. `fields` are extra
+    /// `"name": value` pairs for the message, comma separated.
+    pub fn broadcast<'a>(
+        &mut self,
+        kind: &str,
+        fields: &str,
+        painters: impl Iterator<Item = (u64, &'a Responder)>,
+    ) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut message = format!(
+            "{{\"msg\": \"{EVENT}\", \"id\": {}, \"kind\": \"{}\"",
+            id,
+            escape(kind)
+        );
+        if !fields.is_empty() {
+            message.push_str(", ");
+            message.push_str(fields);
+        }
+        message.push('}');
+
+        let waiting = painters
+            .filter(|(_, responder)| responder.send(Message::Text(message.clone())))
+            .map(|(painter, _)| painter)
+            .collect();
+        self.recent.push_back(Event {
+            id,
+            kind: String::from(kind),
+            sent_at: Instant::now(),
+            waiting,
+            acked: BTreeSet::new(),
+        });
+        if self.recent.len() > KEEP_EVENTS {
+            self.recent.pop_front();
+        }
+        id
+    }
+
+    /// Record a painter acknowledging an event. False if it wasn't waiting for it.
+    pub fn ack(&mut self, id: u64, painter: u64) -> bool {
+        let Some(event) = self.recent.iter_mut().find(|event| event.id == id) else {
+            return false;
+        };
+        if !event.waiting.remove(&painter) {
+            return false;
+        }
+        event.acked.insert(painter);
+        true
+    }
+
+    /// Recent events, oldest first, with who has and hasn't acknowledged them
+    pub fn to_json(&self) -> String {
+        let ids = |set: &BTreeSet<u64>| {
+            set.iter()
+                .map(u64::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        self.recent
+            .iter()
+            .map(|event| {
+                format!(
+                    "{{\"id\": {}, \"kind\": \"{}\", \"age\": {}, \"acked\": [{}], \"waiting\": [{}]}}",
+                    event.id,
+                    escape(&event.kind),
+                    event.sent_at.elapsed().as_secs(),
+                    ids(&event.acked),
+                    ids(&event.waiting)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::test_responder;
+
+    #[test]
+    fn test_broadcast_and_ack() {
+        let (amy, amy_sent) = test_responder();
+        let (bob, _bob_sent) = test_responder();
+        let mut events = Events::default();
+        let id = events.broadcast(
+            "palette",
+            "\"colours\": [1, 2]",
+            [(1, &amy), (2, &bob)].into_iter(),
+        );
+        match amy_sent() {
+            Some(Message::Text(text)) => assert_eq!(
+                text,
+                "{\"msg\": \"event\", \"id\": 1, \"kind\": \"palette\", \"colours\": [1, 2]}"
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(events.ack(id, 2));
+        assert!(!events.ack(id, 2));
+        assert!(!events.ack(id + 1, 1));
+        assert!(events
+            .to_json()
+            .contains("\"acked\": [2], \"waiting\": [1]"));
+    }
+
+    #[test]
+    fn test_old_events_are_forgotten() {
+        let (amy, _amy_sent) = test_responder();
+        let mut events = Events::default();
+        for _ in 0..=KEEP_EVENTS {
+            events.broadcast("tick", "", [(1, &amy)].into_iter());
+        }
+        assert!(!events.ack(1, 1));
+        assert!(events.ack(2, 1));
+    }
+}
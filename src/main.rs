@@ -1,16 +1,49 @@
-use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::{Arc, RwLock};
-use std::{thread, time};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use simple_websockets::{Event, Message, Responder};
+use crate::buffer::{UpdateError, MAX_CLIENTS, TILE_BYTES};
+use crate::config::Config;
+use crate::moderation::{BanKey, Moderation, Score, Verdict};
+use crate::pacing::{Beat, Latency, Pacing};
+use crate::protocol::{
+    CANVAS, NEIGHBOURS, PAINTER, POSITION, SEND_ME_PIXELS, SIZE, TICK_BYTES, WHO_ARE_YOU,
+};
+use crate::ratelimit::TokenBucket;
+use crate::resume::{Sessions, Suspended, RESUME};
+use crate::room::{Room, Rooms, DEFAULT_ROOM};
+use crate::scale::View;
+use crate::turns::Turn;
+use crate::websocket::{Event, Message, Responder};
 
+mod admin;
+mod auth;
+mod bots;
 mod buffer;
+mod config;
+mod events;
+mod moderation;
+mod pacing;
+mod protocol;
+mod ratelimit;
+mod resume;
+mod room;
+mod scale;
+mod snapshot;
+mod tls;
+mod turns;
+mod websocket;
 
 enum ClientData {
     Painter,
+    /// A painter queued for a tile in a full room
+    Waiting,
     Canvas,
-    Unknown,
+    Admin,
+    /// Yet to answer `?`: how many times it has been asked, and when last
+    Unknown { asked: u32, asked_at: Instant },
 }
 
 struct Client {
@@ -18,7 +51,23 @@ struct Client {
     responder: Responder,
     name: String,
     url: String,
-    naughty: u32,
+    room: String,
+    /// The size canvases want their frames at
+    view: View,
+    ip: IpAddr,
+    origin: Option<String>,
+    resume_token: Option<String>,
+    naughty: Score,
+    last_active: Instant,
+    /// `p` polls (or, for push painters, room poll beats) since the last frame
+    unanswered_polls: u32,
+    pacing: Pacing,
+    latency: Latency,
+    /// When the painter got its tile, for taking turns
+    turn: Turn,
+    upload_limit: TokenBucket,
+    pixel_request_limit: TokenBucket,
+    message_limit: TokenBucket,
 }
 
 enum Action {
@@ -26,216 +75,725 @@ enum Action {
     SendMessage(Message),
 }
 
-const WHO_ARE_YOU: &str = "?";
-const SEND_ME_PIXELS: &str = "p";
-const NAUGHTY_WARNING: u32 = 50;
+/// How often the poller checks whether any room is due a poll
+const POLL_TICK: Duration = Duration::from_millis(50);
 
-fn poll_painters(clients: Arc<RwLock<HashMap<u64, Client>>>) {
+fn poll_painters(
+    clients: Arc<RwLock<HashMap<u64, Client>>>,
+    rooms: Arc<RwLock<Rooms>>,
+    config: Arc<Config>,
+) {
     loop {
-        thread::sleep(time::Duration::from_secs(1));
+        thread::sleep(POLL_TICK);
         {
-            let cs = clients.read().unwrap();
-            for client in (*cs).values() {
-                if let ClientData::Painter = &client.data {
-                    client
-                        .responder
-                        .send(Message::Text(format!("{{\"msg\": \"{SEND_ME_PIXELS}\"}}")));
+            let mut cs = clients.write().unwrap();
+            let mut rooms = rooms.write().unwrap();
+            chase_unidentified(&mut cs, &config);
+            let mut idle = Vec::new();
+            for (room_name, room) in rooms.iter_mut() {
+                for client_id in room.sessions.expire() {
+                    println!("Painter #{} did not resume in time", client_id);
+                    room.buffer.remove(client_id);
+                }
+                if let Some(length) = config.turn {
+                    take_turns(&mut cs, room_name, room, length);
+                }
+                for client_id in room.promote() {
+                    if let Some(client) = cs.get_mut(&client_id) {
+                        println!("Painter #{} got a tile in {}", client_id, room_name);
+                        welcome_painter(client, room, None);
+                    }
+                }
+                if room.queue_moved() {
+                    for (position, client_id) in room.waiting.iter().enumerate() {
+                        if let Some(client) = cs.get(client_id) {
+                            client.responder.send(Message::Text(Room::full_message(position + 1)));
+                        }
+                    }
+                }
+                if room.layout_changed() {
+                    for (client_id, client) in cs.iter().filter(|(_, c)| &c.room == room_name) {
+                        let painter = match client.data {
+                            ClientData::Painter => Some(*client_id),
+                            ClientData::Canvas => None,
+                            _ => continue,
+                        };
+                        client.responder.send(Message::Text(room.layout_message(painter)));
+                    }
+                }
+                let room_due = room.poll_due();
+                if room_due {
+                    // Frames still waiting for the rest of their tick have run out of time
+                    if let Err(UpdateError::Server(message) | UpdateError::Client(message)) =
+                        room.buffer.show_tick(room.tick)
+                    {
+                        eprintln!("Error showing tick {} in {}: {}", room.tick, room_name, message);
+                    }
+                    room.tick += 1;
+                    if let Err(message) = room.draw_bots() {
+                        eprintln!("{}", message);
+                    }
+                }
+                for (client_id, client) in cs.iter_mut().filter(|(_, c)| &c.room == room_name) {
+                    if let ClientData::Painter = &client.data {
+                        // Push painters and painters still working on a frame aren't sent `p`,
+                        // but still miss beats if they go quiet
+                        let beat = client.pacing.beat(room_due);
+                        if beat == Beat::Rest {
+                            continue;
+                        }
+                        if config.evict_after_polls > 0
+                            && client.unanswered_polls >= config.evict_after_polls
+                        {
+                            idle.push(*client_id);
+                            continue;
+                        }
+                        if config.stale_after_polls > 0
+                            && client.unanswered_polls >= config.stale_after_polls
+                        {
+                            room.buffer.set_stale(*client_id, true);
+                        }
+                        client.unanswered_polls += 1;
+                        if beat == Beat::Poll {
+                            client
+                                .responder
+                                .send(Message::Text(format!("{{\"msg\": \"{SEND_ME_PIXELS}\", \"tick\": {}}}", room.tick)));
+                        }
+                    }
                 }
             }
+            for client_id in idle {
+                if let Some(client) = cs.remove(&client_id) {
+                    println!(
+                        "Evicting idle painter #{} ({}s since last activity)",
+                        client_id,
+                        client.last_active.elapsed().as_secs()
+                    );
+                    reject(&client.responder, "Evicted: no pixels received");
+                    rooms.remove(&client.room, client_id);
+                }
+            }
+            rooms.prune(|name| cs.values().any(|c| c.room == name));
+        }
+    }
+}
+
+/// Periodically save every room's tiles, with their painters' details, to the snapshot file
+fn snapshot_canvas(
+    clients: Arc<RwLock<HashMap<u64, Client>>>,
+    rooms: Arc<RwLock<Rooms>>,
+    config: Arc<Config>,
+) {
+    let Some(path) = &config.snapshot else {
+        return;
+    };
+    loop {
+        thread::sleep(config.snapshot_interval);
+        let tiles: Vec<_> = {
+            let cs = clients.read().unwrap();
+            let rooms = rooms.read().unwrap();
+            rooms
+                .iter()
+                .flat_map(|(room_name, room)| {
+                    // Bots start afresh with the server, so aren't saved
+                    room.buffer.tiles().filter(|(id, _)| !room.bots.contains_key(id)).map(|(id, pixels)| {
+                        let (name, url, token) = match (cs.get(&id), room.sessions.find(id)) {
+                            (Some(client), _) => (&client.name, &client.url, client.resume_token.clone()),
+                            (None, Some((token, painter))) => (&painter.name, &painter.url, Some(token.clone())),
+                            (None, None) => (&String::new(), &String::new(), None),
+                        };
+                        snapshot::Tile {
+                            room: room_name.clone(),
+                            name: name.clone(),
+                            url: url.clone(),
+                            token: token.unwrap_or_default(),
+                            pixels: pixels.to_vec(),
+                        }
+                    })
+                })
+                .collect()
+        };
+        if let Err(e) = snapshot::save(path, &tiles) {
+            eprintln!("Cannot save snapshot to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Put the tiles from the snapshot file back, waiting for their painters to resume them
+fn restore_canvas(rooms: &mut Rooms, config: &Config) -> Result<(), String> {
+    let Some(path) = config.snapshot.as_ref().filter(|_| config.restore) else {
+        return Ok(());
+    };
+    let tiles = snapshot::load(path)?;
+    println!("Restoring {} tiles from {}", tiles.len(), path.display());
+    for (i, tile) in tiles.into_iter().enumerate() {
+        // Without a token nobody could ever resume the tile
+        if tile.token.is_empty() {
+            println!("Skipping {}'s tile in {}: it has no resume token", tile.name, tile.room);
+            continue;
+        }
+        // Count down from the top so restored ids never clash with new connections
+        let id = u64::MAX - i as u64;
+        let room = rooms.get_or_create(&tile.room);
+        room.buffer.restore(id, &tile.pixels)?;
+        room.sessions
+            .suspend_for(tile.token, Suspended::new(id, tile.name, tile.url), config.restore_grace);
+    }
+    Ok(())
+}
+
+/// Re-ask unidentified clients who have not answered `?` in time, closing those who never do
+fn chase_unidentified(cs: &mut HashMap<u64, Client>, config: &Config) {
+    let mut silent = Vec::new();
+    for (client_id, client) in cs.iter_mut() {
+        if let ClientData::Unknown { asked, asked_at } = &mut client.data {
+            if asked_at.elapsed() < config.handshake_timeout {
+                continue;
+            }
+            if *asked > config.handshake_retries {
+                silent.push(*client_id);
+            } else {
+                *asked += 1;
+                *asked_at = Instant::now();
+                client
+                    .responder
+                    .send(Message::Text(format!("{{\"msg\": \"{WHO_ARE_YOU}\"}}")));
+            }
+        }
+    }
+    for client_id in silent {
+        if let Some(client) = cs.remove(&client_id) {
+            println!("Closing client #{}: never answered ?", client_id);
+            reject(&client.responder, "Handshake timed out");
+        }
+    }
+}
+
+fn handle_error(message: String, client: &mut Client, moderation: &Moderation) -> Action {
+    let message = protocol::escape(&message);
+    let json = |error: String, naughty: u32| {
+        Action::SendMessage(Message::Text(format!(
+            "{{\"msg\": \"error\", \"error\": \"{}\", \"naughty\": {}}}",
+            error, naughty
+        )))
+    };
+    match client.naughty.add(&moderation.policy) {
+        Verdict::Warning(naughty) => json(message, naughty),
+        Verdict::Cooldown(naughty, cooldown) => json(
+            format!("COOLDOWN {}s {}", cooldown.as_secs(), message),
+            naughty,
+        ),
+        Verdict::FinalWarning(naughty) => json(format!("FINAL WARNING {}", message), naughty),
+        Verdict::Kick => Action::RemoveClient,
+    }
+}
+
+/// While someone is waiting for a tile, warn painters whose turns are ending and send the
+/// longest-serving painters whose turns are over to the back of the queue
+fn take_turns(cs: &mut HashMap<u64, Client>, room_name: &str, room: &mut Room, length: Duration) {
+    let painters = cs
+        .iter_mut()
+        .filter(|(_, c)| c.room == room_name && matches!(c.data, ClientData::Painter));
+    if room.waiting.is_empty() {
+        // Turns only count while someone is waiting
+        painters.for_each(|(_, client)| client.turn.restart());
+        return;
+    }
+    let mut over = Vec::new();
+    for (client_id, client) in painters {
+        if let Some(seconds_left) = client.turn.warning(length) {
+            client.responder.send(Message::Text(turns::turn_message(seconds_left)));
+        }
+        if client.turn.over(length) {
+            over.push((client.turn.since(), *client_id));
         }
     }
+    over.sort();
+    let replacements = room.waiting.len();
+    for (i, (_, client_id)) in over.into_iter().enumerate() {
+        let Some(client) = cs.get_mut(&client_id) else {
+            continue;
+        };
+        if i >= replacements {
+            client.turn.restart();
+            continue;
+        }
+        // Told its place in the queue once the next painter has taken over the tile
+        println!("Painter #{}'s turn in {} is over", client_id, room_name);
+        room.buffer.remove(client_id);
+        room.wait(client_id);
+        client.data = ClientData::Waiting;
+    }
+}
+
+fn size_message() -> Message {
+    Message::Text(format!(
+        "{{\"msg\": \"{SIZE}\", \"w\": {}, \"h\": {}}}",
+        buffer::BUFFER_PIXELS,
+        buffer::BUFFER_PIXELS
+    ))
+}
+
+/// Start a painter on its tile: tell it the size and, if painters can resume, its token
+fn welcome_painter(client: &mut Client, room: &Room, resumed_token: Option<String>) {
+    client.data = ClientData::Painter;
+    client.turn = Turn::default();
+    client.responder.send(size_message());
+    if room.sessions.enabled() {
+        let token = resumed_token.unwrap_or_else(Sessions::new_token);
+        client.responder.send(Message::Text(format!(
+            "{{\"msg\": \"{RESUME}\", \"token\": \"{}\", \"grace_ms\": {}}}",
+            protocol::escape(&token),
+            room.sessions.grace().as_millis()
+        )));
+        client.resume_token = Some(token);
+    }
+}
+
+/// Tell a client why it is being disconnected, then disconnect it
+fn reject(responder: &Responder, reason: &str) {
+    responder.send(Message::Text(protocol::error(reason)));
+    responder.close();
 }
 
-fn handle_error(message: String, client: &mut Client) -> Action {
-    client.naughty += 1;
-    let json = format!(
-        "{{\"msg\": \"error\", \"error\": \"FINAL WARNING {}\", \"naughty\": {}}}",
-        message.replace("\"", "\\\""),
-        client.naughty
-    );
-    match client.naughty.cmp(&NAUGHTY_WARNING) {
-        Ordering::Less => Action::SendMessage(Message::Text(json.replace("FINAL WARNING ", ""))),
-        Ordering::Equal => Action::SendMessage(Message::Text(json)),
-        Ordering::Greater => Action::RemoveClient,
+/// Apply `handle_error` to a client, warning or removing it as appropriate
+fn punish(
+    message: String,
+    client_id: u64,
+    cs: &mut HashMap<u64, Client>,
+    rooms: &mut Rooms,
+    moderation: &mut Moderation,
+) {
+    if let Some(client) = cs.get_mut(&client_id) {
+        match handle_error(message, client, moderation) {
+            Action::RemoveClient => {
+                println!("Kicking client #{} for being naughty", client_id);
+                let mut keys = vec![BanKey::Ip(client.ip)];
+                if !client.name.is_empty() {
+                    keys.push(BanKey::Name(client.name.clone()));
+                }
+                moderation.record_kick(keys);
+                client.responder.close();
+                rooms.remove(&client.room, client_id);
+                cs.remove(&client_id);
+            }
+            Action::SendMessage(msg) => {
+                client.responder.send(msg);
+            }
+        }
+    } else {
+        eprintln!("Unknown client id {}", client_id);
     }
 }
 
 fn main() {
-    let event_hub = simple_websockets::launch(8080).expect("failed to listen on port 8080");
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => Arc::new(config),
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    let tls = match config.tls.as_ref().map(|tls| tls.acceptor()).transpose() {
+        Ok(tls) => tls,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let event_hub = websocket::launch(config.port, tls)
+        .unwrap_or_else(|e| panic!("failed to listen on port {}: {}", config.port, e));
+    println!("Listening on {}://0.0.0.0:{}", scheme, event_hub.port());
+    let mut moderation = Moderation::new(config.policy.clone());
+    config
+        .bans
+        .iter()
+        .for_each(|key| moderation.ban(key.clone(), None));
     let clients: Arc<RwLock<HashMap<u64, Client>>> = Arc::new(RwLock::new(HashMap::new()));
-    let clients_for_thread = Arc::clone(&clients);
-
-    let mut image_buffer: crate::buffer::Buffer = crate::buffer::Buffer::new();
+    let mut restored_rooms = Rooms::new(&config);
+    if let Err(message) = restore_canvas(&mut restored_rooms, &config) {
+        eprintln!("{}", message);
+        std::process::exit(2);
+    }
+    for (kind, room) in &config.bots {
+        restored_rooms.get_or_create(room);
+        match restored_rooms.add_bot(room, kind.clone()) {
+            Ok(id) => println!("Bot #{} ({}) is painting in {}", id, kind, room),
+            Err(message) => {
+                eprintln!("Cannot start bot {}: {}", kind, message);
+                std::process::exit(2);
+            }
+        }
+    }
+    let rooms: Arc<RwLock<Rooms>> = Arc::new(RwLock::new(restored_rooms));
 
-    thread::spawn(move || {
-        poll_painters(clients_for_thread);
-    });
+    {
+        let clients = Arc::clone(&clients);
+        let rooms = Arc::clone(&rooms);
+        let config = Arc::clone(&config);
+        thread::spawn(move || {
+            poll_painters(clients, rooms, config);
+        });
+    }
+    {
+        let clients = Arc::clone(&clients);
+        let rooms = Arc::clone(&rooms);
+        let config = Arc::clone(&config);
+        thread::spawn(move || {
+            snapshot_canvas(clients, rooms, config);
+        });
+    }
 
     loop {
-        match event_hub.poll_event() {
-            Event::Connect(client_id, responder) => {
-                println!("A client connected with id #{}", client_id);
-                {
-                    let mut cs = clients.write().unwrap();
-                    cs.insert(
-                        client_id,
-                        Client {
-                            data: ClientData::Unknown,
-                            responder: responder.clone(),
-                            name: Default::default(),
-                            url: Default::default(),
-                            naughty: 0,
-                        },
-                    );
+        let event = event_hub.poll_event();
+        // Always lock clients before rooms
+        let mut cs = clients.write().unwrap();
+        let mut rooms = rooms.write().unwrap();
+        match event {
+            Event::Connect(client_id, responder, peer) => {
+                println!("A client connected with id #{} from {}", client_id, peer.addr);
+                if moderation.is_banned(&BanKey::Ip(peer.addr.ip())) {
+                    println!("Rejecting client #{}: banned", client_id);
+                    reject(&responder, "Banned");
+                    continue;
+                }
+                if config.max_connections > 0 && cs.len() >= config.max_connections {
+                    println!("Rejecting client #{}: server full", client_id);
+                    reject(&responder, "Too many connections");
+                    continue;
+                }
+                let from_ip = cs.values().filter(|c| c.ip == peer.addr.ip()).count();
+                if config.max_connections_per_ip > 0 && from_ip >= config.max_connections_per_ip {
+                    println!("Rejecting client #{}: too many connections from {}", client_id, peer.addr.ip());
+                    reject(&responder, "Too many connections from your address");
+                    continue;
+                }
+                let unidentified = cs
+                    .values()
+                    .filter(|c| matches!(c.data, ClientData::Unknown { .. }))
+                    .count();
+                if config.max_unidentified > 0 && unidentified >= config.max_unidentified {
+                    println!("Rejecting client #{}: too many unidentified connections", client_id);
+                    reject(&responder, "Too many unidentified connections");
+                    continue;
                 }
+                cs.insert(
+                    client_id,
+                    Client {
+                        data: ClientData::Unknown { asked: 1, asked_at: Instant::now() },
+                        responder: responder.clone(),
+                        name: Default::default(),
+                        url: Default::default(),
+                        room: String::from(DEFAULT_ROOM),
+                        view: View::default(),
+                        ip: peer.addr.ip(),
+                        origin: peer.origin.clone(),
+                        resume_token: None,
+                        naughty: Score::default(),
+                        last_active: Instant::now(),
+                        unanswered_polls: 0,
+                        pacing: Pacing::default(),
+                        latency: Latency::default(),
+                        turn: Turn::default(),
+                        upload_limit: TokenBucket::new(config.upload_rate),
+                        pixel_request_limit: TokenBucket::new(config.pixel_request_rate),
+                        message_limit: TokenBucket::new(config.message_rate),
+                    },
+                );
                 responder.send(Message::Text(format!("{{\"msg\": \"{WHO_ARE_YOU}\"}}")));
             }
             Event::Disconnect(client_id) => {
                 println!("Client #{} disconnected.", client_id);
-                {
-                    let mut cs = clients.write().unwrap();
-                    cs.remove(&client_id);
+                match cs.remove(&client_id) {
+                    Some(Client { data: ClientData::Painter, resume_token: Some(token), name, url, room, .. }) => {
+                        if let Some(room) = rooms.get_mut(&room) {
+                            println!("Keeping painter #{}'s tile for it to resume", client_id);
+                            room.buffer.set_stale(client_id, true);
+                            room.sessions.suspend(token, Suspended::new(client_id, name, url));
+                        }
+                    }
+                    Some(client) => rooms.remove(&client.room, client_id),
+                    None => {}
                 }
-                image_buffer.remove(client_id);
             }
-            Event::Message(client_id, message) => match message {
-                Message::Binary(pixels) => {
-                    if let Err(error) = image_buffer.update(client_id, pixels) {
-                        match error {
-                            crate::buffer::UpdateError::Server(message) => {
-                                eprintln!("Error updating pixels for {}: {}", client_id, message);
-                            },
-                            crate::buffer::UpdateError::Client(message) => {
-                                let mut cs = clients.write().unwrap();
-                                if let Some(client) = cs.get_mut(&client_id) {
-                                    match handle_error(message, client) {
-                                        Action::RemoveClient => {
-                                            client.responder.close();
-                                            cs.remove(&client_id);
-                                            image_buffer.remove(client_id);
-                                        },
-                                        Action::SendMessage(msg) => {
-                                            client.responder.send(msg);
-                                        }
-                                    }
+            Event::Message(client_id, message) => {
+                if let Some(client) = cs.get_mut(&client_id) {
+                    client.last_active = Instant::now();
+                    let allowed = match &message {
+                        Message::Binary(_) => client.upload_limit.try_take(),
+                        Message::Text(_) => client.message_limit.try_take(),
+                    };
+                    if !allowed {
+                        let kind = match &message {
+                            Message::Binary(_) => "pixel uploads",
+                            Message::Text(_) => "messages",
+                        };
+                        let message = format!("Rate limit exceeded: too many {}", kind);
+                        punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                        continue;
+                    }
+                }
+                match message {
+                    Message::Binary(_) if cs.get(&client_id).is_some_and(|c| c.naughty.in_cooldown()) => {
+                        // Cooling down: uploads are ignored
+                    }
+                    Message::Binary(_) if cs.get(&client_id).is_some_and(|c| matches!(c.data, ClientData::Waiting)) => {
+                        // Frames still on their way when the painter's turn ended: nowhere to put them
+                    }
+                    Message::Binary(_) if !cs.get(&client_id).is_some_and(|c| matches!(c.data, ClientData::Painter)) => {
+                        let message = String::from("Only painters may send pixels");
+                        punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                    }
+                    Message::Binary(pixels) => {
+                        let Some(client) = cs.get_mut(&client_id) else {
+                            continue;
+                        };
+                        if !client.pacing.accept_frame(client.unanswered_polls) {
+                            // Out of turn: poll painters wait for `p`, push painters for their next frame slot
+                            continue;
+                        }
+                        if let Some(latency) = client.pacing.answered() {
+                            client.latency.record(latency);
+                        }
+                        let Some(room) = rooms.get_mut(&client.room) else {
+                            continue;
+                        };
+                        // Frames for a tick start with the tick as a 4-byte big-endian number
+                        let updated = if pixels.len() == TILE_BYTES + TICK_BYTES {
+                            let tick = u32::from_be_bytes([pixels[0], pixels[1], pixels[2], pixels[3]]);
+                            room.buffer.update_tick(client_id, tick as u64, pixels[TICK_BYTES..].to_vec())
+                        } else {
+                            room.buffer.update(client_id, pixels)
+                        };
+                        if let Err(error) = updated {
+                            match error {
+                                UpdateError::Server(message) => {
+                                    eprintln!("Error updating pixels for {}: {}", client_id, message);
+                                }
+                                UpdateError::Client(message) => {
+                                    punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
                                 }
                             }
+                        } else if let Some(client) = cs.get_mut(&client_id) {
+                            client.unanswered_polls = 0;
                         }
                     }
-                }
-                Message::Text(text) => {
-                    let _ = jsonic::parse(&text)
-                        .map_err(|e| {
-                            let mut cs = clients.write().unwrap();
-                            if let Some(client) = cs.get_mut(&client_id) {
-                                match handle_error(format!("(Cannot parse) {}", e), client) {
-                                    Action::RemoveClient => {
-                                        client.responder.close();
-                                        cs.remove(&client_id);
-                                        image_buffer.remove(client_id);
-                                    },
-                                    Action::SendMessage(msg) => {
-                                        client.responder.send(msg);
-                                    }
-                                }
-                            } else {
+                    Message::Text(text) => match jsonic::parse(&text) {
+                        Err(e) => {
+                            punish(format!("(Cannot parse) {}", e), client_id, &mut cs, &mut rooms, &mut moderation);
+                        }
+                        Ok(sent) => {
+                            let Some(client) = cs.get_mut(&client_id) else {
                                 eprintln!("Unknown client id {}", client_id);
-                            }
-                        })
-                        .map(|sent| {
-                            let mut cs = clients.write().unwrap();
-                            if let Some(client) = cs.get_mut(&client_id) {
-                                match sent["msg"].as_str() {
-                                    Some(WHO_ARE_YOU) => {
-                                        let size_message = Message::Text(format!(
-                                            "{{\"msg\": \"size\", \"w\": {}, \"h\": {}}}",
-                                            buffer::BUFFER_PIXELS,
-                                            buffer::BUFFER_PIXELS
-                                        ));
-
-                                        match sent[WHO_ARE_YOU].as_str() {
-                                            Some("painter") => {
-                                                client.data = ClientData::Painter;
-                                                client.name =
-                                                    String::from(sent["name"].as_str().unwrap_or_default());
-                                                client.url =
-                                                    String::from(sent["url"].as_str().unwrap_or_default());
-                                                if let Err(error) = image_buffer.insert(client_id) {
-                                                    eprintln!("{}", error);
-                                                    client.responder.close();
-                                                    cs.remove(&client_id);
-                                                } else {
-                                                    client.responder.send(size_message);
+                                continue;
+                            };
+                            match sent["msg"].as_str() {
+                                Some(WHO_ARE_YOU) => {
+                                    let role = sent[WHO_ARE_YOU].as_str();
+                                    let room_name = sent["room"].as_str().unwrap_or(DEFAULT_ROOM);
+                                    if matches!(role, Some(PAINTER | CANVAS | admin::ADMIN)) {
+                                        if let ClientData::Painter | ClientData::Waiting = client.data {
+                                            // Painters re-introducing themselves give up their tile or place in the queue,
+                                            // and are unidentified until the new introduction is accepted
+                                            rooms.remove(&client.room, client_id);
+                                            client.data = ClientData::Unknown { asked: 1, asked_at: Instant::now() };
+                                        }
+                                        if let Err(reason) = rooms.join(room_name) {
+                                            println!("Rejecting client #{}: {}", client_id, reason);
+                                            reject(&client.responder, &reason);
+                                            cs.remove(&client_id);
+                                            continue;
+                                        }
+                                        client.room = String::from(room_name);
+                                    }
+                                    let Some(room) = rooms.get_mut(&client.room) else {
+                                        continue;
+                                    };
+
+                                    match role {
+                                        Some(PAINTER) => {
+                                            let name = sent["name"].as_str().unwrap_or_default();
+                                            if !name.is_empty() && moderation.is_banned(&BanKey::Name(String::from(name))) {
+                                                println!("Rejecting painter {}: banned", name);
+                                                reject(&client.responder, "Banned");
+                                                cs.remove(&client_id);
+                                                continue;
+                                            }
+                                            if let Err(reason) = config.tokens.check(sent["token"].as_str(), Some(name)) {
+                                                println!("Rejecting painter {}: {}", name, reason);
+                                                reject(&client.responder, &reason);
+                                                cs.remove(&client_id);
+                                                continue;
+                                            }
+                                            match Pacing::from_handshake(&sent, config.max_fps) {
+                                                Ok(pacing) => client.pacing = pacing,
+                                                Err(message) => {
+                                                    punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                                    continue;
                                                 }
-                                            },
-                                            Some("canvas") => {
-                                                client.data = ClientData::Canvas;
-                                                client.responder.send(size_message);
-                                            },
-                                            Some(who) => {
-                                                match handle_error(format!("{} is not a valid ?. Should be painter or canvas", who), client) {
-                                                    Action::RemoveClient => {
-                                                        client.responder.close();
-                                                        cs.remove(&client_id);
-                                                        image_buffer.remove(client_id);
-                                                    },
-                                                    Action::SendMessage(msg) => {
-                                                        client.responder.send(msg);
+                                            }
+                                            client.name = String::from(name);
+                                            client.url =
+                                                String::from(sent["url"].as_str().unwrap_or_default());
+                                            let mut resumed_token = None;
+                                            if let Some((token, painter)) = room.sessions.resume(sent[RESUME].as_str()) {
+                                                if room.buffer.rekey(painter.id, client_id) {
+                                                    println!("Client #{} resumed painter #{}", client_id, painter.id);
+                                                    if client.name.is_empty() {
+                                                        client.name = painter.name;
                                                     }
-                                                }
-                                            },
-                                            None => {
-                                                match handle_error(String::from("Expected field ?"), client) {
-                                                    Action::RemoveClient => {
-                                                        client.responder.close();
-                                                        cs.remove(&client_id);
-                                                        image_buffer.remove(client_id);
-                                                    },
-                                                    Action::SendMessage(msg) => {
-                                                        client.responder.send(msg);
+                                                    if client.url.is_empty() {
+                                                        client.url = painter.url;
                                                     }
+                                                    resumed_token = Some(token);
+                                                } else {
+                                                    room.buffer.remove(painter.id);
                                                 }
                                             }
+                                            if resumed_token.is_none() && room.buffer.n_clients() >= MAX_CLIENTS {
+                                                let position = room.wait(client_id);
+                                                println!("Painter #{} is waiting for a tile in {} (position {})", client_id, client.room, position);
+                                                client.data = ClientData::Waiting;
+                                                client.responder.send(Message::Text(Room::full_message(position)));
+                                                continue;
+                                            }
+                                            if resumed_token.is_none() {
+                                                if let Err(error) = room.buffer.insert(client_id) {
+                                                    eprintln!("{}", error);
+                                                    reject(&client.responder, &error);
+                                                    cs.remove(&client_id);
+                                                    continue;
+                                                }
+                                            }
+                                            welcome_painter(client, room, resumed_token);
                                         }
-                                    },
-                                    Some(SEND_ME_PIXELS) => {
-                                        let image = <&Vec::<u8>>::from(&image_buffer);
-                                        if !image.is_empty() {
-                                            let mut message =
-                                                (image_buffer.dim() as u16).to_be_bytes().to_vec();
-                                            message.extend(image);
-                                            client.responder.send(Message::Binary(message));
-                                        }
-                                    },
-                                    Some(msg) => {
-                                        match handle_error(format!("Unknown message: {}", msg), client) {
-                                            Action::RemoveClient => {
-                                                client.responder.close();
+                                        Some(CANVAS) => {
+                                            let origin = client.origin.as_deref().unwrap_or_default();
+                                            if !config.allowed_origins.is_empty() && !config.allowed_origins.iter().any(|o| o == origin) {
+                                                println!("Rejecting canvas #{}: origin {:?} not allowed", client_id, origin);
+                                                reject(&client.responder, "Origin not allowed");
+                                                cs.remove(&client_id);
+                                                continue;
+                                            }
+                                            if let Err(reason) = config.tokens.check(sent["token"].as_str(), None) {
+                                                println!("Rejecting canvas #{}: {}", client_id, reason);
+                                                reject(&client.responder, &reason);
                                                 cs.remove(&client_id);
-                                                image_buffer.remove(client_id);
-                                            },
-                                            Action::SendMessage(msg) => {
-                                                client.responder.send(msg);
+                                                continue;
+                                            }
+                                            match View::from_handshake(&sent) {
+                                                Ok(view) => client.view = view,
+                                                Err(message) => {
+                                                    punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                                    continue;
+                                                }
                                             }
+                                            client.data = ClientData::Canvas;
+                                            client.responder.send(size_message());
+                                            client.responder.send(Message::Text(room.layout_message(None)));
                                         }
-                                    },
-                                    None => {
-                                        match handle_error(String::from("Invalid message"), client) {
-                                            Action::RemoveClient => {
-                                                client.responder.close();
+                                        Some(admin::ADMIN) => {
+                                            let password = sent["password"].as_str();
+                                            if config.admin_password.is_some() && password == config.admin_password.as_deref() {
+                                                println!("Client #{} is an admin", client_id);
+                                                client.data = ClientData::Admin;
+                                                client.responder.send(size_message());
+                                            } else {
+                                                println!("Rejecting client #{}: bad admin password", client_id);
+                                                reject(&client.responder, "Not authorised as admin");
                                                 cs.remove(&client_id);
-                                                image_buffer.remove(client_id);
-                                            },
-                                            Action::SendMessage(msg) => {
-                                                client.responder.send(msg);
                                             }
                                         }
+                                        Some(who) => {
+                                            let message = format!("{} is not a valid ?. Should be painter, canvas or admin", who);
+                                            punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                        }
+                                        None => {
+                                            punish(String::from("Expected field ?"), client_id, &mut cs, &mut rooms, &mut moderation);
+                                        }
+                                    }
+                                }
+                                Some(admin::ADMIN) => {
+                                    if let ClientData::Admin = client.data {
+                                        let room_name = sent["room"].as_str().map(String::from).unwrap_or_else(|| client.room.clone());
+                                        let reply = admin::command(&sent, &room_name, &mut cs, &mut rooms, &mut moderation);
+                                        if let Some(client) = cs.get(&client_id) {
+                                            client.responder.send(Message::Text(reply));
+                                        }
+                                    } else {
+                                        let message = String::from("Only admins may send admin commands");
+                                        punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                    }
+                                }
+                                Some(SEND_ME_PIXELS) if !matches!(client.data, ClientData::Canvas | ClientData::Admin) => {
+                                    // Canvases are checked for tokens and origins when they identify
+                                    let message = String::from("Only canvases may ask for the image");
+                                    punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                }
+                                Some(msg @ (SEND_ME_PIXELS | NEIGHBOURS)) if !client.pixel_request_limit.try_take() => {
+                                    let message = format!("Rate limit exceeded: too many {} requests", msg);
+                                    punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                }
+                                Some(SEND_ME_PIXELS) => {
+                                    let Some(room) = rooms.get_mut(&client.room) else {
+                                        continue;
+                                    };
+                                    if room.buffer.n_clients() > 0 {
+                                        let frame = room.frames.frame(&room.buffer, client.view);
+                                        client.responder.send(Message::Binary(frame.to_vec()));
+                                    }
+                                }
+                                Some(events::ACK) => {
+                                    let id = sent["id"].as_i128().and_then(|id| u64::try_from(id).ok());
+                                    if let (Some(id), Some(room)) = (id, rooms.get_mut(&client.room)) {
+                                        // Acks for forgotten events are harmless, so aren't punished
+                                        room.events.ack(id, client_id);
+                                    } else {
+                                        punish(String::from("ack expects an event id"), client_id, &mut cs, &mut rooms, &mut moderation);
+                                    }
+                                }
+                                Some(POSITION | NEIGHBOURS) if matches!(client.data, ClientData::Waiting) => {
+                                    // Asked just before the painter's turn ended, or while it waits: no tile to tell it about
+                                }
+                                Some(POSITION) => {
+                                    match rooms.get(&client.room).and_then(|room| room.buffer.position(client_id)) {
+                                        Some((col, row)) => {
+                                            client.responder.send(Message::Text(format!(
+                                                "{{\"msg\": \"{POSITION}\", \"col\": {}, \"row\": {}}}",
+                                                col, row
+                                            )));
+                                        }
+                                        None => {
+                                            let message = String::from("Only painters have a position");
+                                            punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                        }
+                                    }
+                                }
+                                Some(NEIGHBOURS) => {
+                                    let whole = sent["tiles"].as_bool().unwrap_or(false);
+                                    match rooms.get(&client.room).and_then(|room| room.buffer.neighbours(client_id, whole)) {
+                                        Some(pixels) => {
+                                            client.responder.send(Message::Binary(pixels));
+                                        }
+                                        None => {
+                                            let message = String::from("Only painters have neighbours");
+                                            punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                        }
                                     }
                                 }
+                                Some(msg) => {
+                                    let message = format!("Unknown message: {}", msg);
+                                    punish(message, client_id, &mut cs, &mut rooms, &mut moderation);
+                                }
+                                None => {
+                                    punish(String::from("Invalid message"), client_id, &mut cs, &mut rooms, &mut moderation);
+                                }
                             }
-                        });
+                        }
+                    },
                 }
-            },
+            }
         }
     }
 }
@@ -0,0 +1,258 @@
+/************** Moderation **************
+ * Naughty scores decay over time. Past  *
+ * thresholds a client is put on a       *
+ * cooldown, given a final warning and   *
+ * finally kicked. Repeat offenders are  *
+ * banned by painter name, and by IP if  *
+ * the organisers ask for it.            *
+ ****************************************/
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct Policy {
+    /// Score at which uploads are ignored for `cooldown`
+    pub cooldown_at: f64,
+    pub cooldown: Duration,
+    /// Score at which the FINAL WARNING is sent
+    pub warning_at: f64,
+    /// Score past which the client is kicked
+    pub kick_at: f64,
+    /// Points forgiven per second
+    pub decay_per_sec: f64,
+    /// Kicks before an IP or painter name is banned (0 = never ban)
+    pub kicks_before_ban: u32,
+    /// Ban IPs as well as names. Off by default, since behind a tunnel everyone shares an IP.
+    pub ban_ips: bool,
+    /// How long a ban lasts (None = until restart)
+    pub ban_duration: Option<Duration>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            cooldown_at: 25.0,
+            cooldown: Duration::from_secs(10),
+            warning_at: 50.0,
+            kick_at: 50.0,
+            decay_per_sec: 0.1,
+            kicks_before_ban: 3,
+            ban_ips: false,
+            ban_duration: Some(Duration::from_secs(10 * 60)),
+        }
+    }
+}
+
+pub enum Verdict {
+    Warning(u32),
+    Cooldown(u32, Duration),
+    FinalWarning(u32),
+    Kick,
+}
+
+/// A client's decaying naughty score
+pub struct Score {
+    value: f64,
+    updated: Instant,
+    cooldown_until: Option<Instant>,
+    cooled: bool,
+    warned: bool,
+}
+
+impl Default for Score {
+    fn default() -> Self {
+        Score {
+            value: 0.0,
+            updated: Instant::now(),
+            cooldown_until: None,
+            cooled: false,
+            warned: false,
+        }
+    }
+}
+
+impl Score {
+    pub fn add(&mut self, policy: &Policy) -> Verdict {
+        self.add_at(policy, Instant::now())
+    }
+
+    fn add_at(&mut self, policy: &Policy, now: Instant) -> Verdict {
+        self.value = self.current_at(policy, now) + 1.0;
+        self.updated = now;
+        if self.value < policy.cooldown_at {
+            self.cooled = false;
+        }
+        if self.value < policy.warning_at {
+            self.warned = false;
+        }
+
+        let shown = self.value.round() as u32;
+        if self.value > policy.kick_at {
+            Verdict::Kick
+        } else if self.value >= policy.warning_at && !self.warned {
+            self.warned = true;
+            Verdict::FinalWarning(shown)
+        } else if self.value >= policy.cooldown_at && !self.cooled {
+            self.cooled = true;
+            self.cooldown_until = Some(now + policy.cooldown);
+            Verdict::Cooldown(shown, policy.cooldown)
+        } else {
+            Verdict::Warning(shown)
+        }
+    }
+
+    pub fn current(&self, policy: &Policy) -> f64 {
+        self.current_at(policy, Instant::now())
+    }
+
+    fn current_at(&self, policy: &Policy, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.value - elapsed * policy.decay_per_sec).max(0.0)
+    }
+
+    pub fn in_cooldown(&self) -> bool {
+        self.cooldown_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum BanKey {
+    Ip(IpAddr),
+    Name(String),
+}
+
+impl std::fmt::Display for BanKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanKey::Ip(ip) => write!(f, "ip {}", ip),
+            BanKey::Name(name) => write!(f, "name {}", name),
+        }
+    }
+}
+
+/// Bans and kick history, which outlive any single connection
+pub struct Moderation {
+    pub policy: Policy,
+    bans: HashMap<BanKey, Option<Instant>>,
+    kicks: HashMap<BanKey, u32>,
+}
+
+impl Moderation {
+    pub fn new(policy: Policy) -> Moderation {
+        Moderation {
+            policy,
+            bans: HashMap::new(),
+            kicks: HashMap::new(),
+        }
+    }
+
+    pub fn ban(&mut self, key: BanKey, duration: Option<Duration>) {
+        println!("Banning {}", key);
+        self.bans.insert(key, duration.map(|d| Instant::now() + d));
+    }
+
+    pub fn is_banned(&mut self, key: &BanKey) -> bool {
+        match self.bans.get(key) {
+            Some(Some(until)) if Instant::now() >= *until => {
+                self.bans.remove(key);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Count a kick against each key, banning any that have been kicked too often
+    pub fn record_kick(&mut self, keys: Vec<BanKey>) {
+        for key in keys {
+            if let BanKey::Ip(_) = key {
+                if !self.policy.ban_ips {
+                    continue;
+                }
+            }
+            let kicks = self.kicks.entry(key.clone()).or_insert(0);
+            *kicks += 1;
+            if self.policy.kicks_before_ban > 0 && *kicks >= self.policy.kicks_before_ban {
+                self.kicks.remove(&key);
+                self.ban(key, self.policy.ban_duration);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escalation() {
+        let policy = Policy {
+            cooldown_at: 2.0,
+            warning_at: 3.0,
+            kick_at: 3.0,
+            decay_per_sec: 0.0,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut score = Score::default();
+        assert!(matches!(score.add_at(&policy, now), Verdict::Warning(1)));
+        assert!(matches!(
+            score.add_at(&policy, now),
+            Verdict::Cooldown(2, _)
+        ));
+        assert!(matches!(
+            score.add_at(&policy, now),
+            Verdict::FinalWarning(3)
+        ));
+        assert!(matches!(score.add_at(&policy, now), Verdict::Kick));
+    }
+
+    #[test]
+    fn test_decay() {
+        let policy = Policy {
+            decay_per_sec: 1.0,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut score = Score::default();
+        (0..5).for_each(|_| {
+            score.add_at(&policy, now);
+        });
+        assert_eq!(score.current_at(&policy, now + Duration::from_secs(3)), 2.0);
+        assert_eq!(
+            score.current_at(&policy, now + Duration::from_secs(10)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_ban_after_kicks() {
+        let mut moderation = Moderation::new(Policy {
+            kicks_before_ban: 2,
+            ..Default::default()
+        });
+        let name = BanKey::Name(String::from("vandal"));
+        moderation.record_kick(vec![name.clone()]);
+        assert!(!moderation.is_banned(&name));
+        moderation.record_kick(vec![name.clone()]);
+        assert!(moderation.is_banned(&name));
+    }
+
+    #[test]
+    fn test_ip_bans_are_opt_in() {
+        let ip = BanKey::Ip(IpAddr::from([127, 0, 0, 1]));
+        let mut moderation = Moderation::new(Policy {
+            kicks_before_ban: 1,
+            ..Default::default()
+        });
+        moderation.record_kick(vec![ip.clone()]);
+        assert!(!moderation.is_banned(&ip));
+
+        moderation.policy.ban_ips = true;
+        moderation.record_kick(vec![ip.clone()]);
+        assert!(moderation.is_banned(&ip));
+    }
+}
@@ -0,0 +1,253 @@
+/************** Frame pacing **************
+ * Painters choose in their `?` reply how  *
+ * their frames are paced: `poll` painters *
+ * send one frame per `p` poll, at the     *
+ * room's rate or their own interval;      *
+ * `push` painters send whenever they like *
+ * up to the server's maximum FPS.         *
+ * Poll painters still working on a frame  *
+ * are not polled again, and how long they *
+ * take to answer is recorded.             *
+ ****************************************/
+
+use std::time::{Duration, Instant};
+
+use jsonic::json_item::JsonItem;
+
+/// Beats a poll painter may skip while working on a frame before it is polled again anyway
+const POLL_PATIENCE: u32 = 3;
+/// Weight of each new response time in the running mean
+const MEAN_WEIGHT: f64 = 0.2;
+
+/// What the poller should do about a painter this tick
+#[derive(Debug, PartialEq)]
+pub enum Beat {
+    /// Nothing yet
+    Rest,
+    /// Send `p`
+    Poll,
+    /// Count a beat without sending `p`: the painter pushes, or is still busy
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// Send a frame in reply to each `p`, polled every `interval` or with the room
+    Poll {
+        interval: Option<Duration>,
+        polled_at: Instant,
+        /// When the unanswered `p` was sent
+        awaiting: Option<Instant>,
+        skipped: u32,
+    },
+    /// Send frames unprompted, at least `min_gap` apart
+    Push {
+        min_gap: Duration,
+        last_frame: Option<Instant>,
+    },
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Pacing::Poll {
+            interval: None,
+            polled_at: Instant::now(),
+            awaiting: None,
+            skipped: 0,
+        }
+    }
+}
+
+impl Pacing {
+    /// The pacing a painter asked for with `"mode"` and, for `poll`, `"interval"` in ms
+    pub fn from_handshake(sent: &JsonItem, max_fps: f64) -> Result<Pacing, String> {
+        let min_gap = if max_fps > 0.0 {
+            Duration::from_secs_f64(1.0 / max_fps)
+        } else {
+            Duration::ZERO
+        };
+        match sent["mode"].as_str() {
+            None | Some("poll") => {
+                let interval = match sent["interval"].as_i128() {
+                    Some(ms) if ms > 0 => Some(Duration::from_millis(ms as u64).max(min_gap)),
+                    None if !sent["interval"].exists() => None,
+                    _ => return Err(String::from("interval must be a positive number of ms")),
+                };
+                Ok(Pacing::Poll {
+                    interval,
+                    polled_at: Instant::now(),
+                    awaiting: None,
+                    skipped: 0,
+                })
+            }
+            Some("push") => Ok(Pacing::Push {
+                min_gap,
+                last_frame: None,
+            }),
+            Some(mode) => Err(format!(
+                "{} is not a valid mode. Should be poll or push",
+                mode
+            )),
+        }
+    }
+
+    /// What to do about the painter, given whether its room is due a poll
+    pub fn beat(&mut self, room_due: bool) -> Beat {
+        match self {
+            Pacing::Poll {
+                interval,
+                polled_at,
+                awaiting,
+                skipped,
+            } => {
+                let due = match interval {
+                    None => room_due,
+                    Some(interval) => polled_at.elapsed() >= *interval,
+                };
+                if !due {
+                    return Beat::Rest;
+                }
+                *polled_at = Instant::now();
+                if awaiting.is_some() && *skipped < POLL_PATIENCE {
+                    *skipped += 1;
+                    return Beat::Skip;
+                }
+                *awaiting = Some(Instant::now());
+                *skipped = 0;
+                Beat::Poll
+            }
+            Pacing::Push { .. } if room_due => Beat::Skip,
+            Pacing::Push { .. } => Beat::Rest,
+        }
+    }
+
+    /// Whether to take a frame now: `poll` painters only answer outstanding polls,
+    /// `push` painters are held to the maximum FPS. Frames out of turn are dropped.
+    pub fn accept_frame(&mut self, unanswered_polls: u32) -> bool {
+        match self {
+            Pacing::Poll { .. } => unanswered_polls > 0,
+            Pacing::Push {
+                min_gap,
+                last_frame,
+            } => {
+                let now = Instant::now();
+                if last_frame.is_some_and(|last| now.duration_since(last) < *min_gap) {
+                    return false;
+                }
+                *last_frame = Some(now);
+                true
+            }
+        }
+    }
+
+    /// How long an accepted frame took to arrive after its `p`
+    pub fn answered(&mut self) -> Option<Duration> {
+        match self {
+            Pacing::Poll { awaiting, .. } => awaiting.take().map(|sent| sent.elapsed()),
+            Pacing::Push { .. } => None,
+        }
+    }
+}
+
+/// Response times for a painter's frames
+#[derive(Debug, Default)]
+pub struct Latency {
+    last: Duration,
+    mean: Duration,
+    max: Duration,
+    frames: u64,
+}
+
+impl Latency {
+    pub fn record(&mut self, latency: Duration) {
+        self.mean = if self.frames == 0 {
+            latency
+        } else {
+            self.mean.mul_f64(1.0 - MEAN_WEIGHT) + latency.mul_f64(MEAN_WEIGHT)
+        };
+        self.last = latency;
+        self.max = self.max.max(latency);
+        self.frames += 1;
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"last_ms\": {}, \"mean_ms\": {}, \"max_ms\": {}, \"frames\": {}}}",
+            self.last.as_millis(),
+            self.mean.as_millis(),
+            self.max.as_millis(),
+            self.frames
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<Pacing, String> {
+        Pacing::from_handshake(&jsonic::parse(json).unwrap(), 5.0)
+    }
+
+    #[test]
+    fn test_handshake() {
+        assert!(matches!(
+            parse("{}"),
+            Ok(Pacing::Poll { interval: None, .. })
+        ));
+        // Faster than the maximum FPS is slowed down to it
+        assert!(matches!(
+            parse(r#"{"mode": "poll", "interval": 50}"#),
+            Ok(Pacing::Poll { interval: Some(interval), .. }) if interval == Duration::from_millis(200)
+        ));
+        assert!(matches!(
+            parse(r#"{"mode": "push"}"#),
+            Ok(Pacing::Push { .. })
+        ));
+        assert!(parse(r#"{"mode": "shove"}"#).is_err());
+        assert!(parse(r#"{"interval": -1}"#).is_err());
+    }
+
+    #[test]
+    fn test_accept_frame() {
+        let mut poll = Pacing::default();
+        assert!(!poll.accept_frame(0));
+        assert!(poll.accept_frame(1));
+
+        let mut push = parse(r#"{"mode": "push"}"#).unwrap();
+        assert!(push.accept_frame(0));
+        assert!(!push.accept_frame(0));
+        assert_eq!(push.beat(true), Beat::Skip);
+        assert_eq!(push.beat(false), Beat::Rest);
+        assert!(push.answered().is_none());
+    }
+
+    #[test]
+    fn test_busy_painter_is_skipped() {
+        let mut poll = Pacing::default();
+        assert_eq!(poll.beat(false), Beat::Rest);
+        assert_eq!(poll.beat(true), Beat::Poll);
+        for _ in 0..POLL_PATIENCE {
+            assert_eq!(poll.beat(true), Beat::Skip);
+        }
+        // Polled again in case the last `p` went astray
+        assert_eq!(poll.beat(true), Beat::Poll);
+        assert!(poll.answered().is_some());
+        assert!(poll.answered().is_none());
+        assert_eq!(poll.beat(true), Beat::Poll);
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut latency = Latency::default();
+        latency.record(Duration::from_millis(100));
+        latency.record(Duration::from_millis(200));
+        assert_eq!(latency.last, Duration::from_millis(200));
+        assert_eq!(latency.mean, Duration::from_millis(120));
+        assert_eq!(latency.max, Duration::from_millis(200));
+        assert_eq!(
+            latency.to_json(),
+            "{\"last_ms\": 200, \"mean_ms\": 120, \"max_ms\": 200, \"frames\": 2}"
+        );
+    }
+}
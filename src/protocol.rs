@@ -0,0 +1,46 @@
+/************** Protocol **************
+ * JSON helpers for talking to        *
+ * painters and canvases. Message     *
+ * names come from `jeeves_client` so *
+ * the server and client agree.       *
+ **************************************/
+
+use jsonic::json_item::JsonItem;
+use jsonic::json_type::JsonType;
+
+pub use jeeves_client::protocol::{
+    escape, ACK, CANVAS, ERROR, EVENT, FULL, LAYOUT, NEIGHBOURS, PAINTER, PIXEL_SIZE, POSITION,
+    RESUME, SEND_ME_PIXELS, SIZE, TICK_BYTES, TILE_BYTES, TILE_PIXELS, TURN, WHO_ARE_YOU,
+};
+
+/// A parsed value as JSON again, taken from the text it was parsed from
+pub fn raw(item: &JsonItem) -> Option<String> {
+    let text = item.as_str()?;
+    Some(match item.get_type() {
+        JsonType::JsonString => format!("\"{}\"", text),
+        _ => String::from(text),
+    })
+}
+
+/// An error message for a client
+pub fn error(message: &str) -> String {
+    format!(
+        "{{\"msg\": \"{ERROR}\", \"error\": \"{}\"}}",
+        escape(message)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw() {
+        let sent =
+            jsonic::parse(r#"{"s": "say \"hi\"", "n": 1.5, "a": [1, {"b": null}]}"#).unwrap();
+        assert_eq!(raw(&sent["s"]).unwrap(), r#""say \"hi\"""#);
+        assert_eq!(raw(&sent["n"]).unwrap(), "1.5");
+        assert_eq!(raw(&sent["a"]).unwrap(), r#"[1, {"b": null}]"#);
+        assert!(raw(&sent["missing"]).is_none());
+    }
+}
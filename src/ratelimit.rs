@@ -0,0 +1,71 @@
+/************** Token bucket rate limiter **************
+ * Each message costs one token; tokens refill at a    *
+ * steady rate up to a burst capacity.                 *
+ *******************************************************/
+
+use std::time::Instant;
+
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Allow `rate` messages per second on average, bursting up to two seconds' worth.
+    /// A rate of zero disables limiting.
+    pub fn new(rate: f64) -> TokenBucket {
+        let capacity = (2.0 * rate).max(1.0);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0);
+        bucket.refilled_at = start;
+        assert!((0..4).all(|_| bucket.try_take_at(start)));
+        assert!(!bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_zero_rate_is_unlimited() {
+        let mut bucket = TokenBucket::new(0.0);
+        assert!((0..1000).all(|_| bucket.try_take()));
+    }
+}
@@ -0,0 +1,125 @@
+/************** Resumable sessions **************
+ * Painters are given a resume token after the   *
+ * `?` handshake. If their connection drops,     *
+ * their slot and tile are kept for a grace      *
+ * period so they can reconnect with the token   *
+ * and carry on where they left off. Only the   *
+ * token gets a tile back, since names needn't   *
+ * be unique.                                    *
+ ***********************************************/
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub use crate::protocol::RESUME;
+
+/// A painter whose connection dropped, waiting to be resumed
+pub struct Suspended {
+    pub id: u64,
+    pub name: String,
+    pub url: String,
+    deadline: Instant,
+}
+
+impl Suspended {
+    pub fn new(id: u64, name: String, url: String) -> Suspended {
+        Suspended {
+            id,
+            name,
+            url,
+            deadline: Instant::now(),
+        }
+    }
+}
+
+pub struct Sessions {
+    grace: Duration,
+    suspended: HashMap<String, Suspended>,
+}
+
+impl Sessions {
+    pub fn new(grace: Duration) -> Sessions {
+        Sessions {
+            grace,
+            suspended: HashMap::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.grace.is_zero()
+    }
+
+    /// How long a dropped painter's tile is kept for it
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    pub fn new_token() -> String {
+        format!("{:032x}", rand::random::<u128>())
+    }
+
+    pub fn suspend(&mut self, token: String, painter: Suspended) {
+        self.suspend_for(token, painter, self.grace);
+    }
+
+    /// Suspend a painter with its own grace period, e.g. one restored from a snapshot
+    pub fn suspend_for(&mut self, token: String, mut painter: Suspended, grace: Duration) {
+        painter.deadline = Instant::now() + grace;
+        self.suspended.insert(token, painter);
+    }
+
+    /// Find a suspended painter by its token
+    pub fn resume(&mut self, token: Option<&str>) -> Option<(String, Suspended)> {
+        self.suspended.remove_entry(token?)
+    }
+
+    /// The token and details of the suspended painter with this id
+    pub fn find(&self, id: u64) -> Option<(&String, &Suspended)> {
+        self.suspended.iter().find(|(_, painter)| painter.id == id)
+    }
+
+    /// Forget painters whose grace period has run out, returning their ids
+    pub fn expire(&mut self) -> Vec<u64> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.suspended.retain(|_, painter| {
+            let keep = now < painter.deadline;
+            if !keep {
+                expired.push(painter.id);
+            }
+            keep
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn painter(id: u64, name: &str) -> Suspended {
+        Suspended::new(id, String::from(name), String::new())
+    }
+
+    #[test]
+    fn test_resume_and_expire() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        sessions.suspend(String::from("a"), painter(1, ""));
+        sessions.suspend_for(String::from("b"), painter(2, ""), Duration::ZERO);
+        assert_eq!(sessions.resume(Some("a")).map(|(_, p)| p.id), Some(1));
+        assert!(sessions.resume(Some("a")).is_none());
+
+        assert_eq!(sessions.expire(), vec![2]);
+        assert!(sessions.resume(Some("b")).is_none());
+    }
+
+    #[test]
+    fn test_name_alone_does_not_resume() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        sessions.suspend(String::from("a"), painter(1, "amy"));
+        assert!(sessions.resume(None).is_none());
+        assert!(sessions.resume(Some("wrong")).is_none());
+        let (token, painter) = sessions.resume(Some("a")).unwrap();
+        assert_eq!((token.as_str(), painter.id), ("a", 1));
+    }
+}
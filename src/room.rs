@@ -0,0 +1,369 @@
+/************** Rooms **************
+ * Independent canvases served by   *
+ * one process. Each room has its   *
+ * own image buffer, resumable      *
+ * painters, bots and poll rate.    *
+ * Rooms are declared up front or   *
+ * created on demand by the `?`     *
+ * handshake.                       *
+ ***********************************/
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::bots::{Bot, Kind};
+use crate::buffer::{Buffer, UpdateError, BUFFER_PIXELS, MAX_CLIENTS};
+use crate::config::Config;
+use crate::events::Events;
+use crate::protocol::{FULL, LAYOUT};
+use crate::resume::Sessions;
+use crate::scale::Frames;
+
+pub const DEFAULT_ROOM: &str = "main";
+const MAX_NAME_LENGTH: usize = 32;
+/// Bots are numbered from here, well clear of connections and restored tiles
+const FIRST_BOT_ID: u64 = 1 << 62;
+
+pub struct Room {
+    pub buffer: Buffer,
+    pub sessions: Sessions,
+    pub frames: Frames,
+    pub events: Events,
+    pub bots: BTreeMap<u64, Bot>,
+    /// Painters waiting for a tile, first in line first
+    pub waiting: VecDeque<u64>,
+    /// Whether anyone has left the queue since waiting painters were told their positions
+    queue_moved: bool,
+    pub poll_interval: Duration,
+    /// Counts poll beats, so painters can tag frames meant to be shown together
+    pub tick: u64,
+    polled_at: Instant,
+    /// The buffer layout painters and canvases were last told about
+    announced_layout: u64,
+    declared: bool,
+}
+
+impl Room {
+    /// Whether it is time to poll this room's painters again
+    pub fn poll_due(&mut self) -> bool {
+        if self.polled_at.elapsed() >= self.poll_interval {
+            self.polled_at = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Give every bot in the room its next frame
+    pub fn draw_bots(&mut self) -> Result<(), String> {
+        for (id, bot) in self.bots.iter_mut() {
+            if let Err(UpdateError::Server(message) | UpdateError::Client(message)) =
+                self.buffer.update(*id, bot.render())
+            {
+                return Err(format!("Bot #{} ({}): {}", id, bot.kind, message));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_bot(&mut self, id: u64) -> bool {
+        let removed = self.bots.remove(&id).is_some();
+        if removed {
+            self.buffer.remove(id);
+        }
+        removed
+    }
+
+    /// Queue a painter for a tile, returning its position in the queue
+    pub fn wait(&mut self, id: u64) -> usize {
+        self.waiting.push_back(id);
+        self.waiting.len()
+    }
+
+    /// Give freed tiles to painters at the front of the queue, returning who got one
+    pub fn promote(&mut self) -> Vec<u64> {
+        let mut promoted = Vec::new();
+        while self.buffer.n_clients() < MAX_CLIENTS {
+            let Some(id) = self.waiting.pop_front() else {
+                break;
+            };
+            if self.buffer.insert(id).is_ok() {
+                promoted.push(id);
+            }
+        }
+        self.queue_moved |= !promoted.is_empty();
+        promoted
+    }
+
+    /// Whether positions in the queue have changed since this was last asked
+    pub fn queue_moved(&mut self) -> bool {
+        std::mem::take(&mut self.queue_moved)
+    }
+
+    /// The `full` message for a painter this far back in the queue
+    pub fn full_message(position: usize) -> String {
+        format!("{{\"msg\": \"{FULL}\", \"position\": {}}}", position)
+    }
+
+    /// Whether tiles have moved since this was last asked
+    pub fn layout_changed(&mut self) -> bool {
+        let changed = self.buffer.layout() != self.announced_layout;
+        self.announced_layout = self.buffer.layout();
+        changed
+    }
+
+    /// The `layout` message for a painter (with its tile's top left pixel) or a canvas
+    pub fn layout_message(&self, painter: Option<u64>) -> String {
+        let dim = self.buffer.dim();
+        let origin = painter
+            .and_then(|id| self.buffer.position(id))
+            .map(|(x, y)| {
+                format!(
+                    ", \"x\": {}, \"y\": {}",
+                    x * BUFFER_PIXELS,
+                    y * BUFFER_PIXELS
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            "{{\"msg\": \"{LAYOUT}\", \"grid\": {}, \"w\": {}, \"h\": {}, \"painters\": {}{}}}",
+            dim / BUFFER_PIXELS,
+            dim,
+            dim,
+            self.buffer.n_clients(),
+            origin
+        )
+    }
+}
+
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+    on_demand: bool,
+    max_rooms: usize,
+    poll_interval: Duration,
+    resume_grace: Duration,
+    next_bot_id: u64,
+}
+
+impl Rooms {
+    pub fn new(config: &Config) -> Rooms {
+        let mut rooms = Rooms {
+            rooms: HashMap::new(),
+            on_demand: config.on_demand_rooms,
+            max_rooms: config.max_rooms,
+            poll_interval: config.poll_interval,
+            resume_grace: config.resume_grace,
+            next_bot_id: FIRST_BOT_ID,
+        };
+        rooms.get_or_create(DEFAULT_ROOM).declared = true;
+        for name in &config.rooms {
+            rooms.get_or_create(name).declared = true;
+        }
+        rooms
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Room> {
+        self.rooms.get_mut(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Room)> {
+        self.rooms.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&String, &mut Room)> {
+        self.rooms.iter_mut()
+    }
+
+    /// The room a client asked for in its handshake, created if allowed
+    pub fn join(&mut self, name: &str) -> Result<&mut Room, String> {
+        if !self.rooms.contains_key(name) {
+            if !self.on_demand {
+                return Err(format!("There is no room called {}", name));
+            }
+            if name.is_empty()
+                || name.len() > MAX_NAME_LENGTH
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(String::from("Room names are 1-32 letters, digits, - or _"));
+            }
+            if self.rooms.len() >= self.max_rooms {
+                return Err(String::from("Too many rooms"));
+            }
+        }
+        Ok(self.get_or_create(name))
+    }
+
+    /// A room by name, created without any checks, e.g. when restoring a snapshot
+    pub fn get_or_create(&mut self, name: &str) -> &mut Room {
+        self.rooms
+            .entry(String::from(name))
+            .or_insert_with(|| Room {
+                buffer: Buffer::new(),
+                sessions: Sessions::new(self.resume_grace),
+                frames: Frames::default(),
+                events: Events::default(),
+                bots: BTreeMap::new(),
+                waiting: VecDeque::new(),
+                queue_moved: false,
+                poll_interval: self.poll_interval,
+                tick: 0,
+                polled_at: Instant::now(),
+                announced_layout: 0,
+                declared: false,
+            })
+    }
+
+    /// Start a bot painting in a room, returning its id
+    pub fn add_bot(&mut self, room_name: &str, kind: Kind) -> Result<u64, String> {
+        let id = self.next_bot_id;
+        let room = self
+            .rooms
+            .get_mut(room_name)
+            .ok_or_else(|| format!("There is no room called {}", room_name))?;
+        room.buffer.insert(id)?;
+        room.bots.insert(id, Bot::new(kind));
+        self.next_bot_id += 1;
+        Ok(id)
+    }
+
+    /// Remove a client's tile, or its place in the queue for one, from its room
+    pub fn remove(&mut self, name: &str, id: u64) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.buffer.remove(id);
+            let queued = room.waiting.len();
+            room.waiting.retain(|&waiting| waiting != id);
+            room.queue_moved |= room.waiting.len() != queued;
+        }
+    }
+
+    /// Drop rooms created on demand once nobody is using them
+    pub fn prune(&mut self, occupied: impl Fn(&str) -> bool) {
+        self.rooms
+            .retain(|name, room| room.declared || room.buffer.n_clients() > 0 || occupied(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::TILE_BYTES;
+    use crate::protocol::PIXEL_SIZE;
+
+    #[test]
+    fn test_join_on_demand() {
+        let mut rooms = Rooms::new(&Config {
+            max_rooms: 2,
+            ..Default::default()
+        });
+        assert!(rooms.join(DEFAULT_ROOM).is_ok());
+        assert!(rooms.join("bad name!").is_err());
+        assert!(rooms.join("lab-1").is_ok());
+        assert!(rooms.join("lab-2").is_err());
+
+        rooms.prune(|_| false);
+        assert!(rooms.get_mut("lab-1").is_none());
+        assert!(rooms.get_mut(DEFAULT_ROOM).is_some());
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut rooms = Rooms::new(&Config::default());
+        let room = rooms.get_mut(DEFAULT_ROOM).unwrap();
+        assert!(!room.layout_changed());
+        room.buffer.insert(7).unwrap();
+        room.buffer.insert(8).unwrap();
+        assert!(room.layout_changed());
+        assert!(!room.layout_changed());
+        assert_eq!(
+            room.layout_message(Some(8)),
+            "{\"msg\": \"layout\", \"grid\": 2, \"w\": 80, \"h\": 80, \"painters\": 2, \"x\": 40, \"y\": 0}"
+        );
+        assert_eq!(
+            room.layout_message(None),
+            "{\"msg\": \"layout\", \"grid\": 2, \"w\": 80, \"h\": 80, \"painters\": 2}"
+        );
+    }
+
+    #[test]
+    fn test_bots() {
+        let mut rooms = Rooms::new(&Config::default());
+        let id = rooms.add_bot(DEFAULT_ROOM, Kind::Gradient).unwrap();
+        assert!(rooms.add_bot("elsewhere", Kind::Gradient).is_err());
+        let room = rooms.get_mut(DEFAULT_ROOM).unwrap();
+        assert!(room.draw_bots().is_ok());
+        assert_eq!(room.buffer.position(id), Some((0, 0)));
+        assert!(room.remove_bot(id));
+        assert!(!room.remove_bot(id));
+        assert_eq!(room.buffer.n_clients(), 0);
+    }
+
+    #[test]
+    fn test_queue_for_a_full_room() {
+        let mut rooms = Rooms::new(&Config::default());
+        let room = rooms.get_mut(DEFAULT_ROOM).unwrap();
+        for id in 0..MAX_CLIENTS as u64 {
+            room.buffer.insert(id).unwrap();
+        }
+        assert_eq!(room.wait(100), 1);
+        assert_eq!(room.wait(101), 2);
+        assert_eq!(room.wait(102), 3);
+        assert!(room.promote().is_empty());
+        assert!(!room.queue_moved());
+
+        rooms.remove(DEFAULT_ROOM, 101);
+        rooms.remove(DEFAULT_ROOM, 5);
+        let room = rooms.get_mut(DEFAULT_ROOM).unwrap();
+        assert_eq!(room.promote(), [100]);
+        assert!(room.queue_moved());
+        assert!(!room.queue_moved());
+        assert_eq!(room.waiting, [102]);
+        assert!(room.buffer.position(100).is_some());
+        assert_eq!(
+            Room::full_message(1),
+            "{\"msg\": \"full\", \"position\": 1}"
+        );
+    }
+
+    #[test]
+    fn test_canvas_clean_after_promote() {
+        let mut rooms = Rooms::new(&Config::default());
+        let room = rooms.get_mut(DEFAULT_ROOM).unwrap();
+        for id in 0..MAX_CLIENTS as u64 {
+            room.buffer.insert(id).unwrap();
+            let _ = room.buffer.update(id, vec![id as u8 + 1; TILE_BYTES]);
+        }
+        room.wait(100);
+        rooms.remove(DEFAULT_ROOM, 5);
+        let room = rooms.get_mut(DEFAULT_ROOM).unwrap();
+        assert_eq!(room.promote(), [100]);
+
+        let dim = room.buffer.dim();
+        let pixels = <&Vec<u8>>::from(&room.buffer);
+        let tile = |id: u64| {
+            let (col, row) = room.buffer.position(id).unwrap();
+            pixels[(row * BUFFER_PIXELS * dim + col * BUFFER_PIXELS) * PIXEL_SIZE]
+        };
+        assert!(!pixels.contains(&6));
+        assert_eq!(tile(100), 0);
+        assert!((0..MAX_CLIENTS as u64)
+            .filter(|&id| id != 5)
+            .all(|id| tile(id) == id as u8 + 1));
+    }
+
+    #[test]
+    fn test_declared_rooms_only() {
+        let mut rooms = Rooms::new(&Config {
+            rooms: vec![String::from("lab")],
+            on_demand_rooms: false,
+            ..Default::default()
+        });
+        assert!(rooms.join("lab").is_ok());
+        assert!(rooms.join("elsewhere").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+/************** Scaled frames **************
+ * Canvases on slow connections can ask for *
+ * a downscaled copy of the room's image,   *
+ * either by a fixed factor or to fit a     *
+ * maximum dimension. Projectors can ask    *
+ * for a nearest-neighbour zoom, optionally *
+ * with grid lines between painter tiles,   *
+ * zoomed less in rooms too big to fit.     *
+ * Frames are cached per view so many       *
+ * spectators at the same size cost one     *
+ * resample per change.                     *
+ ******************************************/
+
+use std::collections::HashMap;
+
+use jsonic::json_item::JsonItem;
+
+use crate::buffer::{Buffer, BUFFER_PIXELS};
+use crate::protocol::PIXEL_SIZE;
+
+const MAX_FACTOR: usize = 64;
+const MAX_ZOOM: usize = 8;
+/// Widest a zoomed image may be, so big rooms aren't blown up into huge frames
+const MAX_ZOOMED_DIM: usize = 1280;
+const GRID_COLOUR: [u8; PIXEL_SIZE] = [32, 32, 32, 255];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    /// Shrink by this factor in each direction
+    Down(usize),
+    /// Shrink just enough to be no wider than this many pixels
+    Fit(usize),
+    /// Blow up each pixel into a square this many pixels wide
+    Up(usize),
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Scale::Down(1)
+    }
+}
+
+impl Scale {
+    /// How many source pixels make one output pixel, and how many output pixels each
+    /// of those becomes, in each direction
+    fn factors(self, dim: usize) -> (usize, usize) {
+        match self {
+            Scale::Down(factor) => (factor, 1),
+            Scale::Fit(max) => (dim.div_ceil(max).clamp(1, MAX_FACTOR), 1),
+            Scale::Up(zoom) => (1, zoom.min(MAX_ZOOMED_DIM / dim.max(1)).max(1)),
+        }
+    }
+}
+
+/// How a canvas wants to see the room
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct View {
+    pub scale: Scale,
+    /// Draw lines between painter tiles
+    pub grid: bool,
+}
+
+impl View {
+    /// The view a canvas asked for in its `?` reply, from `"scale"`, `"max"` or `"zoom"`,
+    /// and `"grid"`
+    pub fn from_handshake(sent: &JsonItem) -> Result<View, String> {
+        let positive = |field: &str| match sent[field].as_i128() {
+            Some(n) if n > 0 => Ok(Some(n as usize)),
+            None if !sent[field].exists() => Ok(None),
+            _ => Err(format!("{} must be a positive whole number", field)),
+        };
+        let scale = match (positive("scale")?, positive("max")?, positive("zoom")?) {
+            (Some(factor), None, None) => Scale::Down(factor.min(MAX_FACTOR)),
+            (None, Some(max), None) => Scale::Fit(max),
+            (None, None, Some(zoom)) if zoom <= MAX_ZOOM => Scale::Up(zoom),
+            (None, None, Some(_)) => return Err(format!("zoom can be at most {}", MAX_ZOOM)),
+            (None, None, None) => Scale::default(),
+            _ => return Err(String::from("Give only one of scale, max or zoom")),
+        };
+        let grid = match sent["grid"].as_bool() {
+            Some(grid) => grid,
+            None if !sent["grid"].exists() => false,
+            None => return Err(String::from("grid must be true or false")),
+        };
+        Ok(View { scale, grid })
+    }
+}
+
+/// Average each `factor` × `factor` block of RGBA pixels into one.
+/// Blocks hanging off the right or bottom edge average the pixels they cover.
+pub fn downscale(pixels: &[u8], dim: usize, factor: usize) -> (usize, Vec<u8>) {
+    if factor <= 1 {
+        return (dim, pixels.to_vec());
+    }
+    let out_dim = dim.div_ceil(factor);
+    let mut out = Vec::with_capacity(out_dim * out_dim * PIXEL_SIZE);
+    for out_y in 0..out_dim {
+        let rows = out_y * factor..((out_y + 1) * factor).min(dim);
+        for out_x in 0..out_dim {
+            let columns = out_x * factor..((out_x + 1) * factor).min(dim);
+            let mut sum = [0u32; PIXEL_SIZE];
+            for y in rows.clone() {
+                for x in columns.clone() {
+                    let i = (y * dim + x) * PIXEL_SIZE;
+                    sum.iter_mut()
+                        .zip(&pixels[i..i + PIXEL_SIZE])
+                        .for_each(|(total, &channel)| *total += channel as u32);
+                }
+            }
+            let count = (rows.len() * columns.len()) as u32;
+            out.extend(sum.map(|total| ((total + count / 2) / count) as u8));
+        }
+    }
+    (out_dim, out)
+}
+
+/// Repeat each RGBA pixel into a `zoom` × `zoom` square
+pub fn upscale(pixels: &[u8], dim: usize, zoom: usize) -> (usize, Vec<u8>) {
+    let out_dim = dim * zoom;
+    let mut out = Vec::with_capacity(out_dim * out_dim * PIXEL_SIZE);
+    for row in pixels.chunks_exact(dim * PIXEL_SIZE) {
+        let line: Vec<u8> = row
+            .chunks_exact(PIXEL_SIZE)
+            .flat_map(|pixel| pixel.repeat(zoom))
+            .collect();
+        for _ in 0..zoom {
+            out.extend(&line);
+        }
+    }
+    (out_dim, out)
+}
+
+/// Draw lines over an image resampled from a `source_dim` buffer, along its tile edges
+fn draw_grid(pixels: &mut [u8], dim: usize, source_dim: usize) {
+    let mut paint = |x: usize, y: usize| {
+        let i = (y * dim + x) * PIXEL_SIZE;
+        pixels[i..i + PIXEL_SIZE].copy_from_slice(&GRID_COLOUR);
+    };
+    for tile in 1..source_dim / BUFFER_PIXELS {
+        let at = tile * BUFFER_PIXELS * dim / source_dim;
+        for along in 0..dim {
+            paint(at, along);
+            paint(along, at);
+        }
+    }
+}
+
+/// Ready-to-send `p` replies (2-byte dimension then pixels) for each view in use.
+/// Factors are capped, so the cache stays small.
+#[derive(Default)]
+pub struct Frames {
+    cache: HashMap<(usize, usize, bool), (u64, Vec<u8>)>,
+}
+
+impl Frames {
+    /// The `p` reply for the buffer in this view, resampled only if the buffer has changed
+    pub fn frame(&mut self, buffer: &Buffer, view: View) -> &[u8] {
+        let (down, up) = view.scale.factors(buffer.dim());
+        let version = buffer.version();
+        let (cached_version, frame) = self.cache.entry((down, up, view.grid)).or_default();
+        if *cached_version != version || frame.is_empty() {
+            let source = <&Vec<u8>>::from(buffer);
+            let (dim, mut pixels) = if up > 1 {
+                upscale(source, buffer.dim(), up)
+            } else {
+                downscale(source, buffer.dim(), down)
+            };
+            if view.grid {
+                draw_grid(&mut pixels, dim, buffer.dim());
+            }
+            frame.clear();
+            frame.extend((dim as u16).to_be_bytes());
+            frame.extend(pixels);
+            *cached_version = version;
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downscale() {
+        // 3 × 3: a white column, then two black ones
+        let pixels: Vec<u8> = (0..9)
+            .flat_map(|i| if i % 3 == 0 { [255; 4] } else { [0, 0, 0, 255] })
+            .collect();
+        let (dim, out) = downscale(&pixels, 3, 2);
+        assert_eq!(dim, 2);
+        assert_eq!(
+            out,
+            vec![128, 128, 128, 255, 0, 0, 0, 255, 128, 128, 128, 255, 0, 0, 0, 255]
+        );
+        assert_eq!(downscale(&pixels, 3, 1), (3, pixels));
+    }
+
+    #[test]
+    fn test_upscale() {
+        let pixels = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        let (dim, out) = upscale(&pixels, 2, 2);
+        assert_eq!(dim, 4);
+        assert_eq!(out[..16], [1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8, 5, 6, 7, 8]);
+        assert_eq!(out[16..32], out[..16]);
+        assert_eq!(out[48..56], [9, 10, 11, 12, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn test_factors() {
+        assert_eq!(Scale::Down(2).factors(320), (2, 1));
+        assert_eq!(Scale::Fit(100).factors(320), (4, 1));
+        assert_eq!(Scale::Fit(400).factors(320), (1, 1));
+        assert_eq!(Scale::Up(3).factors(320), (1, 3));
+        assert_eq!(Scale::Up(8).factors(40), (1, 8));
+        assert_eq!(Scale::Up(8).factors(320), (1, 4));
+    }
+
+    #[test]
+    fn test_grid() {
+        let mut pixels = vec![0; 80 * 80 * PIXEL_SIZE];
+        draw_grid(&mut pixels, 80, 80);
+        let at = |x: usize, y: usize| &pixels[(y * 80 + x) * PIXEL_SIZE..][..PIXEL_SIZE];
+        assert_eq!(at(40, 3), GRID_COLOUR);
+        assert_eq!(at(3, 40), GRID_COLOUR);
+        assert_eq!(at(39, 39), [0; PIXEL_SIZE]);
+    }
+
+    #[test]
+    fn test_frames_follow_buffer() {
+        let mut buffer = Buffer::new();
+        buffer.insert(0).unwrap();
+        let mut frames = Frames::default();
+        assert_eq!(
+            frames.frame(
+                &buffer,
+                View {
+                    scale: Scale::Down(2),
+                    grid: false
+                }
+            )[..2],
+            [0, 20]
+        );
+        assert_eq!(
+            frames.frame(
+                &buffer,
+                View {
+                    scale: Scale::Down(2),
+                    grid: false
+                }
+            )[2],
+            0
+        );
+
+        buffer
+            .update(0, vec![200; crate::buffer::TILE_BYTES])
+            .ok()
+            .unwrap();
+        assert_eq!(
+            frames.frame(
+                &buffer,
+                View {
+                    scale: Scale::Down(2),
+                    grid: false
+                }
+            )[2],
+            200
+        );
+    }
+
+    #[test]
+    fn test_zoom_after_painter_leaves() {
+        let mut buffer = Buffer::new();
+        (0..2).for_each(|id| buffer.insert(id).unwrap());
+        buffer.remove(1);
+        let view = View {
+            scale: Scale::Up(2),
+            grid: false,
+        };
+        let mut frames = Frames::default();
+        let frame = frames.frame(&buffer, view);
+        assert_eq!(frame[..2], (80u16).to_be_bytes());
+        assert_eq!(frame.len(), 2 + 80 * 80 * PIXEL_SIZE);
+        let (dim, out) = upscale(<&Vec<u8>>::from(&buffer), buffer.dim(), 2);
+        assert_eq!(out.len(), dim * dim * PIXEL_SIZE);
+    }
+}
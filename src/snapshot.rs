@@ -0,0 +1,144 @@
+/************** Canvas snapshots **************
+ * Painter tiles, in slot order, with their    *
+ * room, name, url and resume token, saved to  *
+ * a file so a restarted server can put them   *
+ * back.                                       *
+ *                                             *
+ * Format: "JEEVES2\n", u32 tile count, then   *
+ * per tile: u16-length-prefixed room, name,   *
+ * url and token followed by the raw RGBA      *
+ * pixels. Integers are big-endian. "JEEVES1"  *
+ * snapshots have no room and load into the    *
+ * default room.                               *
+ **********************************************/
+
+use std::path::Path;
+
+use crate::buffer::TILE_BYTES;
+use crate::room::DEFAULT_ROOM;
+
+const MAGIC: &[u8] = b"JEEVES2\n";
+const MAGIC_V1: &[u8] = b"JEEVES1\n";
+
+pub struct Tile {
+    pub room: String,
+    pub name: String,
+    pub url: String,
+    pub token: String,
+    pub pixels: Vec<u8>,
+}
+
+/// Write the snapshot to a temporary file then move it into place,
+/// so a crash part way through never leaves a truncated snapshot
+pub fn save(path: &Path, tiles: &[Tile]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, encode(tiles))?;
+    std::fs::rename(&temporary, path)
+}
+
+pub fn load(path: &Path) -> Result<Vec<Tile>, String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("Cannot read snapshot {}: {}", path.display(), e))?;
+    decode(&bytes)
+        .ok_or_else(|| format!("{} is not a valid snapshot", path.display()))
+}
+
+fn encode(tiles: &[Tile]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend((tiles.len() as u32).to_be_bytes());
+    for tile in tiles {
+        for text in [&tile.room, &tile.name, &tile.url, &tile.token] {
+            let text = &text.as_bytes()[..text.len().min(u16::MAX as usize)];
+            bytes.extend((text.len() as u16).to_be_bytes());
+            bytes.extend(text);
+        }
+        bytes.extend(&tile.pixels[..TILE_BYTES]);
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<Vec<Tile>> {
+    let (mut rest, has_room) = match bytes.strip_prefix(MAGIC) {
+        Some(rest) => (rest, true),
+        None => (bytes.strip_prefix(MAGIC_V1)?, false),
+    };
+    let mut take = |n: usize| {
+        let (taken, remaining) = rest.split_at_checked(n)?;
+        rest = remaining;
+        Some(taken)
+    };
+    let count = u32::from_be_bytes(take(4)?.try_into().ok()?);
+    let mut tiles = Vec::new();
+    for _ in 0..count {
+        let mut text = || {
+            let len = u16::from_be_bytes(take(2)?.try_into().ok()?) as usize;
+            String::from_utf8(take(len)?.to_vec()).ok()
+        };
+        let room = if has_room {
+            text()?
+        } else {
+            String::from(DEFAULT_ROOM)
+        };
+        let (name, url, token) = (text()?, text()?, text()?);
+        let pixels = take(TILE_BYTES)?.to_vec();
+        tiles.push(Tile {
+            room,
+            name,
+            url,
+            token,
+            pixels,
+        });
+    }
+    Some(tiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let tiles = vec![
+            Tile {
+                room: String::from("lab"),
+                name: String::from("amy"),
+                url: String::from("https://example.com"),
+                token: String::from("abc"),
+                pixels: vec![1; TILE_BYTES],
+            },
+            Tile {
+                room: String::from(DEFAULT_ROOM),
+                name: String::new(),
+                url: String::new(),
+                token: String::new(),
+                pixels: vec![2; TILE_BYTES],
+            },
+        ];
+        let bytes = encode(&tiles);
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].room, "lab");
+        assert_eq!(decoded[0].name, "amy");
+        assert_eq!(decoded[0].url, "https://example.com");
+        assert_eq!(decoded[0].token, "abc");
+        assert_eq!(decoded[1].pixels, vec![2; TILE_BYTES]);
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_none());
+        assert!(decode(b"nonsense").is_none());
+    }
+
+    #[test]
+    fn test_version_one() {
+        let mut bytes = MAGIC_V1.to_vec();
+        bytes.extend(1u32.to_be_bytes());
+        bytes.extend([0, 3]);
+        bytes.extend(b"amy");
+        bytes.extend([0, 0, 0, 0]);
+        bytes.extend(vec![9; TILE_BYTES]);
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded[0].room, DEFAULT_ROOM);
+        assert_eq!(decoded[0].name, "amy");
+        assert_eq!(decoded[0].pixels, vec![9; TILE_BYTES]);
+    }
+}
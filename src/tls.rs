@@ -0,0 +1,74 @@
+/************** TLS termination **************
+ * Serve wss:// directly from a certificate   *
+ * and key in PEM files, or from a freshly    *
+ * generated self-signed certificate.         *
+ ********************************************/
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+pub enum Tls {
+    Files { cert: String, key: String },
+    /// A certificate for these host names, generated at startup
+    SelfSigned(Vec<String>),
+}
+
+impl Tls {
+    pub fn acceptor(&self) -> Result<TlsAcceptor, String> {
+        let (certs, key) = match self {
+            Tls::Files { cert, key } => (read_certs(cert)?, read_key(key)?),
+            Tls::SelfSigned(hosts) => self_signed(hosts.clone())?,
+        };
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Cannot open {}: {}", path, e))
+}
+
+fn read_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| format!("Cannot read certificates from {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &str) -> Result<PrivateKey, String> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(rustls_pemfile::Item::PKCS8Key(key)))
+            | Ok(Some(rustls_pemfile::Item::RSAKey(key)))
+            | Ok(Some(rustls_pemfile::Item::ECKey(key))) => return Ok(PrivateKey(key)),
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(format!("No private key found in {}", path)),
+            Err(e) => return Err(format!("Cannot read private key from {}: {}", path, e)),
+        }
+    }
+}
+
+fn self_signed(hosts: Vec<String>) -> Result<(Vec<Certificate>, PrivateKey), String> {
+    let cert = rcgen::generate_simple_self_signed(hosts)
+        .map_err(|e| format!("Cannot generate a self-signed certificate: {}", e))?;
+    let der = cert
+        .serialize_der()
+        .map_err(|e| format!("Cannot serialise the self-signed certificate: {}", e))?;
+    Ok((
+        vec![Certificate(der)],
+        PrivateKey(cert.serialize_private_key_der()),
+    ))
+}
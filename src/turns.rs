@@ -0,0 +1,123 @@
+/************** Painting turns **************
+ * With `--turn-secs`, painters in a full    *
+ * room take turns. Turns start once someone *
+ * is waiting; when a turn is over the       *
+ * longest-serving painters go to the back   *
+ * of the queue, one for each painter        *
+ * waiting, and the rest get another turn.   *
+ * Painters are warned as their turn ends.   *
+ *******************************************/
+
+use std::time::{Duration, Instant};
+
+use crate::protocol::TURN;
+
+/// Seconds before the end of a turn that painters are warned, longest first
+const WARNINGS: [u64; 3] = [60, 30, 10];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Turn {
+    /// When the painter got its tile
+    since: Instant,
+    started: Instant,
+    /// How many of `WARNINGS` have been dealt with
+    warned: usize,
+}
+
+impl Default for Turn {
+    fn default() -> Self {
+        Turn {
+            since: Instant::now(),
+            started: Instant::now(),
+            warned: 0,
+        }
+    }
+}
+
+impl Turn {
+    pub fn since(&self) -> Instant {
+        self.since
+    }
+
+    /// Start another turn on the same tile
+    pub fn restart(&mut self) {
+        self.started = Instant::now();
+        self.warned = 0;
+    }
+
+    /// Seconds left to warn the painter about, if a warning has come due
+    pub fn warning(&mut self, length: Duration) -> Option<u64> {
+        self.warning_at(length, Instant::now())
+    }
+
+    fn warning_at(&mut self, length: Duration, now: Instant) -> Option<u64> {
+        let left = length.saturating_sub(now.duration_since(self.started));
+        let mut due = None;
+        while let Some(&seconds) = WARNINGS.get(self.warned) {
+            if Duration::from_secs(seconds) < left {
+                break;
+            }
+            self.warned += 1;
+            // Turns too short for a warning get the later ones only
+            if Duration::from_secs(seconds) < length {
+                due = Some(seconds);
+            }
+        }
+        due
+    }
+
+    pub fn over(&self, length: Duration) -> bool {
+        self.started.elapsed() >= length
+    }
+}
+
+/// Tells a painter how long its turn has left
+pub fn turn_message(seconds_left: u64) -> String {
+    format!(
+        "{{\"msg\": \"{TURN}\", \"seconds_left\": {}}}",
+        seconds_left
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings() {
+        let start = Instant::now();
+        let mut turn = Turn {
+            since: start,
+            started: start,
+            warned: 0,
+        };
+        let length = Duration::from_secs(120);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        assert_eq!(turn.warning_at(length, at(30)), None);
+        assert_eq!(turn.warning_at(length, at(60)), Some(60));
+        assert_eq!(turn.warning_at(length, at(61)), None);
+        // Missed warnings are skipped in favour of the latest
+        assert_eq!(turn.warning_at(length, at(115)), Some(10));
+        assert_eq!(turn.warning_at(length, at(119)), None);
+    }
+
+    #[test]
+    fn test_short_turn() {
+        let start = Instant::now();
+        let mut turn = Turn {
+            since: start,
+            started: start,
+            warned: 0,
+        };
+        let length = Duration::from_secs(20);
+        assert_eq!(turn.warning_at(length, start), None);
+        assert_eq!(
+            turn.warning_at(length, start + Duration::from_secs(10)),
+            Some(10)
+        );
+        assert_eq!(
+            turn_message(10),
+            "{\"msg\": \"turn\", \"seconds_left\": 10}"
+        );
+    }
+}
@@ -0,0 +1,218 @@
+/************** Websocket event hub **************
+ * A blocking event queue over an async server.  *
+ * Connections, disconnections and messages are  *
+ * delivered as `Event`s; each client is replied *
+ * to through its `Responder`.                   *
+ *************************************************/
+
+use std::net::SocketAddr;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::{accept_hdr_async, tungstenite};
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Message {
+    fn into_tungstenite(self) -> tungstenite::Message {
+        match self {
+            Message::Text(text) => tungstenite::Message::Text(text),
+            Message::Binary(bytes) => tungstenite::Message::Binary(bytes),
+        }
+    }
+
+    fn from_tungstenite(message: tungstenite::Message) -> Option<Message> {
+        match message {
+            tungstenite::Message::Text(text) => Some(Message::Text(text)),
+            tungstenite::Message::Binary(bytes) => Some(Message::Binary(bytes)),
+            _ => None,
+        }
+    }
+}
+
+enum Command {
+    Send(Message),
+    Close,
+}
+
+#[derive(Debug, Clone)]
+pub struct Responder {
+    tx: flume::Sender<Command>,
+}
+
+impl Responder {
+    /// Queue a message for the client. Returns false if the connection has gone.
+    pub fn send(&self, message: Message) -> bool {
+        self.tx.send(Command::Send(message)).is_ok()
+    }
+
+    pub fn close(&self) {
+        let _ = self.tx.send(Command::Close);
+    }
+}
+
+/// A responder not attached to a connection, and a way to read what was sent to it
+#[cfg(test)]
+pub fn test_responder() -> (Responder, impl Fn() -> Option<Message>) {
+    let (tx, rx) = flume::unbounded();
+    let sent = move || match rx.try_recv() {
+        Ok(Command::Send(message)) => Some(message),
+        _ => None,
+    };
+    (Responder { tx }, sent)
+}
+
+/// What is known about a client when it connects
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// The HTTP Origin header sent by browsers
+    pub origin: Option<String>,
+}
+
+pub enum Event {
+    Connect(u64, Responder, Peer),
+    Disconnect(u64),
+    Message(u64, Message),
+}
+
+pub struct EventHub {
+    rx: flume::Receiver<Event>,
+    port: u16,
+}
+
+impl EventHub {
+    /// Block until the next event arrives
+    pub fn poll_event(&self) -> Event {
+        self.rx
+            .recv()
+            .expect("Websocket listener thread has stopped")
+    }
+
+    /// The port being listened on, which the OS picks when asked for port 0
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// Listen for websocket connections, speaking wss:// if given a TLS acceptor
+pub fn launch(port: u16, tls: Option<TlsAcceptor>) -> std::io::Result<EventHub> {
+    let listener = std::net::TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+    let runtime = Runtime::new()?;
+    let (tx, rx) = flume::unbounded();
+    std::thread::Builder::new()
+        .name(String::from("Websocket listener"))
+        .spawn(move || runtime.block_on(listen(listener, tls, tx)))?;
+    Ok(EventHub { rx, port })
+}
+
+async fn listen(
+    listener: std::net::TcpListener,
+    tls: Option<TlsAcceptor>,
+    events: flume::Sender<Event>,
+) {
+    let listener = TcpListener::from_std(listener).expect("not inside a tokio runtime");
+    let mut next_id: u64 = 0;
+    loop {
+        if let Ok((stream, addr)) = listener.accept().await {
+            tokio::spawn(accept(
+                stream,
+                tls.clone(),
+                Peer { addr, origin: None },
+                next_id,
+                events.clone(),
+            ));
+            next_id = next_id.wrapping_add(1);
+        }
+    }
+}
+
+async fn accept(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    peer: Peer,
+    id: u64,
+    events: flume::Sender<Event>,
+) {
+    match tls {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => handle_connection(stream, peer, id, events).await,
+            Err(e) => eprintln!("TLS handshake with {} failed: {}", peer.addr, e),
+        },
+        None => handle_connection(stream, peer, id, events).await,
+    }
+}
+
+// tungstenite's handshake callback dictates the large error type
+#[allow(clippy::result_large_err)]
+async fn handle_connection<S>(stream: S, mut peer: Peer, id: u64, events: flume::Sender<Event>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let record_origin = |request: &Request, response: Response| {
+        peer.origin = request
+            .headers()
+            .get("origin")
+            .and_then(|origin| origin.to_str().ok())
+            .map(String::from);
+        Ok(response)
+    };
+    let Ok(ws_stream) = accept_hdr_async(stream, record_origin).await else {
+        return;
+    };
+    let (mut outgoing, mut incoming) = ws_stream.split();
+    let (tx, rx) = flume::unbounded();
+
+    if events
+        .send(Event::Connect(id, Responder { tx }, peer))
+        .is_err()
+    {
+        return;
+    }
+
+    let send = async move {
+        while let Ok(command) = rx.recv_async().await {
+            match command {
+                Command::Send(message) => {
+                    if outgoing.send(message.into_tungstenite()).await.is_err() {
+                        break;
+                    }
+                }
+                Command::Close => break,
+            }
+        }
+        let _ = outgoing.close().await;
+    };
+
+    let events_for_receive = events.clone();
+    let receive = async move {
+        while let Some(Ok(message)) = incoming.next().await {
+            if let Some(message) = Message::from_tungstenite(message) {
+                if events_for_receive
+                    .send(Event::Message(id, message))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    };
+
+    // Whichever side finishes first ends the connection
+    tokio::select! {
+        _ = send => {},
+        _ = receive => {},
+    }
+
+    let _ = events.send(Event::Disconnect(id));
+}
@@ -0,0 +1,211 @@
+/************** Test harness **************
+ * Runs the real server on a free port and *
+ * talks to it over real websockets.       *
+ ******************************************/
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use jeeves_client::protocol::{CANVAS, PAINTER, SIZE, WHO_ARE_YOU};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+/// How long to wait for the server before failing a test
+const PATIENCE: Duration = Duration::from_secs(5);
+
+pub struct Server {
+    child: Child,
+    pub port: u16,
+}
+
+impl Server {
+    /// Start the server on a port the OS picks, with extra command line options
+    pub fn start(args: &[&str]) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_jeeves"))
+            .args(["--port", "0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the server");
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let port = lines
+            .by_ref()
+            .map_while(Result::ok)
+            .find_map(|line| {
+                line.strip_prefix("Listening on ")?
+                    .rsplit_once(':')?
+                    .1
+                    .parse()
+                    .ok()
+            })
+            .expect("the server never said which port it is on");
+        // Keep reading, so the server never blocks on a full pipe
+        std::thread::spawn(move || lines.for_each(drop));
+        Server { child, port }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A JSON message from the server. Parsed items point into the text they came
+/// from, so it is kept as text and parsed for each lookup.
+#[derive(Debug)]
+pub struct Json(pub String);
+
+impl Json {
+    pub fn str(&self, field: &str) -> Option<String> {
+        jsonic::parse(&self.0).ok()?[field]
+            .as_str()
+            .map(String::from)
+    }
+
+    pub fn int(&self, field: &str) -> Option<i128> {
+        jsonic::parse(&self.0).ok()?[field].as_i128()
+    }
+
+    pub fn bool(&self, field: &str) -> Option<bool> {
+        jsonic::parse(&self.0).ok()?[field].as_bool()
+    }
+}
+
+pub struct Client {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Client {
+    pub async fn connect(server: &Server) -> Client {
+        let (socket, _) = connect_async(server.url())
+            .await
+            .expect("failed to connect");
+        Client { socket }
+    }
+
+    /// Connect as a page served from `origin`
+    pub async fn connect_from(server: &Server, origin: &str) -> Client {
+        let mut request = server.url().into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Origin", origin.parse().unwrap());
+        let (socket, _) = connect_async(request).await.expect("failed to connect");
+        Client { socket }
+    }
+
+    /// Connect and answer `?` with this role and any extra `"name": value` fields
+    pub async fn hello(server: &Server, role: &str, fields: &str) -> Client {
+        let mut client = Client::connect(server).await;
+        client.expect(WHO_ARE_YOU).await;
+        let separator = if fields.is_empty() { "" } else { ", " };
+        client
+            .send(&format!(
+                "{{\"msg\": \"{WHO_ARE_YOU}\", \"{WHO_ARE_YOU}\": \"{}\"{}{}}}",
+                role, separator, fields
+            ))
+            .await;
+        client
+    }
+
+    /// A painter which has been given its tile
+    pub async fn painter(server: &Server, name: &str) -> Client {
+        let mut client = Client::hello(
+            server,
+            PAINTER,
+            &format!("\"name\": \"{}\", \"url\": \"\"", name),
+        )
+        .await;
+        client.expect(SIZE).await;
+        client
+    }
+
+    pub async fn canvas(server: &Server) -> Client {
+        let mut client = Client::hello(server, CANVAS, "").await;
+        client.expect(SIZE).await;
+        client
+    }
+
+    pub async fn send(&mut self, json: &str) {
+        self.socket
+            .send(Message::Text(String::from(json)))
+            .await
+            .expect("failed to send");
+    }
+
+    pub async fn send_binary(&mut self, bytes: Vec<u8>) {
+        self.socket
+            .send(Message::Binary(bytes))
+            .await
+            .expect("failed to send");
+    }
+
+    /// The next text or binary message, or `None` once the server has closed the connection
+    pub async fn receive(&mut self) -> Option<Message> {
+        loop {
+            let message = tokio::time::timeout(PATIENCE, self.socket.next())
+                .await
+                .expect("the server went quiet");
+            match message {
+                Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) => {
+                    return Some(message)
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    /// The next message, which must be JSON
+    pub async fn receive_json(&mut self) -> Json {
+        match self.receive().await {
+            Some(Message::Text(text)) => {
+                assert!(
+                    jsonic::parse(&text).is_ok(),
+                    "the server sent invalid JSON: {}",
+                    text
+                );
+                Json(text)
+            }
+            other => panic!("expected JSON, got {:?}", other),
+        }
+    }
+
+    /// Skip messages until one with this `msg`
+    pub async fn expect(&mut self, msg: &str) -> Json {
+        loop {
+            let sent = self.receive_json().await;
+            if sent.str("msg").as_deref() == Some(msg) {
+                return sent;
+            }
+        }
+    }
+
+    /// Skip text messages until binary data arrives
+    pub async fn expect_binary(&mut self) -> Vec<u8> {
+        loop {
+            match self.receive().await {
+                Some(Message::Binary(bytes)) => return bytes,
+                Some(_) => {}
+                None => panic!("disconnected while waiting for binary data"),
+            }
+        }
+    }
+
+    /// Wait for the server to close the connection, ignoring anything it sends first
+    pub async fn closed(&mut self) {
+        while self.receive().await.is_some() {}
+    }
+}
@@ -0,0 +1,376 @@
+//! The websocket protocol as painters and canvases see it, against a real server
+
+mod common;
+
+use common::{Client, Server};
+use jeeves_client::protocol::{
+    ERROR, FULL, LAYOUT, PAINTER, PIXEL_SIZE, POSITION, RESUME, SEND_ME_PIXELS, SIZE, TILE_BYTES,
+    TILE_PIXELS, TURN, WHO_ARE_YOU,
+};
+
+#[tokio::test]
+async fn test_painter_handshake() {
+    let server = Server::start(&[]);
+    let mut painter = Client::connect(&server).await;
+    assert_eq!(
+        painter.receive_json().await.str("msg").as_deref(),
+        Some(WHO_ARE_YOU)
+    );
+    painter
+        .send(r#"{"msg": "?", "?": "painter", "name": "Sam", "url": ""}"#)
+        .await;
+    let size = painter.expect(SIZE).await;
+    assert_eq!(size.int("w"), Some(TILE_PIXELS as i128));
+    assert_eq!(size.int("h"), Some(TILE_PIXELS as i128));
+    let resume = painter.expect(RESUME).await;
+    assert!(resume.str("token").is_some());
+    assert_eq!(resume.int("grace_ms"), Some(30_000));
+}
+
+#[tokio::test]
+async fn test_zero_max_unidentified_is_unlimited() {
+    let server = Server::start(&["--max-unidentified", "0"]);
+    Client::painter(&server, "Sam").await;
+}
+
+#[tokio::test]
+async fn test_canvas_handshake() {
+    let server = Server::start(&[]);
+    let mut canvas = Client::hello(&server, "canvas", "").await;
+    let size = canvas.expect(SIZE).await;
+    assert_eq!(size.int("w"), Some(TILE_PIXELS as i128));
+    // An empty room: no painters yet
+    assert_eq!(canvas.expect("layout").await.int("painters"), Some(0));
+}
+
+#[tokio::test]
+async fn test_unknown_role_is_warned() {
+    let server = Server::start(&[]);
+    let mut client = Client::hello(&server, "wizard", "").await;
+    let error = client.expect(ERROR).await;
+    assert!(error
+        .str("error")
+        .unwrap()
+        .starts_with("wizard is not a valid ?"));
+    assert_eq!(error.int("naughty"), Some(1));
+}
+
+#[tokio::test]
+async fn test_pixels_round_trip() {
+    let server = Server::start(&["--poll-interval-ms", "100"]);
+    let mut painter = Client::painter(&server, "Sam").await;
+    painter.expect(SEND_ME_PIXELS).await;
+    let colour = [12, 34, 56, 255];
+    painter.send_binary(colour.repeat(TILE_BYTES / 4)).await;
+
+    let mut canvas = Client::canvas(&server).await;
+    canvas.send(r#"{"msg": "p"}"#).await;
+    let frame = canvas.expect_binary().await;
+    assert_eq!(frame[..2], (TILE_PIXELS as u16).to_be_bytes());
+    assert_eq!(frame.len(), 2 + TILE_PIXELS * TILE_PIXELS * PIXEL_SIZE);
+    assert!(frame[2..]
+        .chunks_exact(PIXEL_SIZE)
+        .all(|pixel| pixel == colour));
+}
+
+#[tokio::test]
+async fn test_unresumed_tile_leaves_the_canvas() {
+    // Painters here only answer one poll, so mustn't be greyed out for missing the rest
+    let server = Server::start(&[
+        "--poll-interval-ms",
+        "100",
+        "--resume-grace-ms",
+        "200",
+        "--stale-after-polls",
+        "0",
+    ]);
+    let mut leaver = Client::painter(&server, "Sam").await;
+    let mut stayer = Client::painter(&server, "Alex").await;
+    for (painter, colour) in [
+        (&mut leaver, [200, 0, 0, 255]),
+        (&mut stayer, [0, 0, 200, 255]),
+    ] {
+        painter.expect(SEND_ME_PIXELS).await;
+        painter.send_binary(colour.repeat(TILE_BYTES / 4)).await;
+    }
+    drop(leaver);
+    tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+
+    let mut canvas = Client::canvas(&server).await;
+    canvas.send(r#"{"msg": "p"}"#).await;
+    let frame = canvas.expect_binary().await;
+    assert_eq!(frame[..2], (TILE_PIXELS as u16).to_be_bytes());
+    assert_eq!(frame.len(), 2 + TILE_PIXELS * TILE_PIXELS * PIXEL_SIZE);
+    assert!(frame[2..]
+        .chunks_exact(PIXEL_SIZE)
+        .all(|pixel| pixel == [0, 0, 200, 255]));
+}
+
+#[tokio::test]
+async fn test_tiles_without_tokens_are_not_restored() {
+    let path = std::env::temp_dir().join(format!("jeeves-restore-{}.bin", std::process::id()));
+    let snapshot = path.to_str().unwrap();
+    {
+        // Without resuming, painters have no token to come back with
+        let server = Server::start(&[
+            "--snapshot",
+            snapshot,
+            "--snapshot-interval-ms",
+            "100",
+            "--resume-grace-ms",
+            "0",
+        ]);
+        let _painter = Client::painter(&server, "Sam").await;
+        tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+    }
+    let server = Server::start(&["--snapshot", snapshot, "--restore"]);
+    let mut canvas = Client::hello(&server, "canvas", "").await;
+    assert_eq!(canvas.expect(LAYOUT).await.int("painters"), Some(0));
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn test_failed_reintroduction_leaves_painter_unidentified() {
+    let server = Server::start(&["--poll-interval-ms", "100", "--handshake-timeout-ms", "300"]);
+    let mut painter = Client::painter(&server, "Sam").await;
+    painter
+        .send(r#"{"msg": "?", "?": "painter", "name": "Sam", "url": "", "mode": "bogus"}"#)
+        .await;
+    painter.expect(ERROR).await;
+    // No more polls for the tile it gave up: it is asked who it is again instead
+    assert_eq!(
+        painter.receive_json().await.str("msg").as_deref(),
+        Some(WHO_ARE_YOU)
+    );
+}
+
+#[tokio::test]
+async fn test_position_is_in_tiles_and_layout_in_pixels() {
+    let server = Server::start(&[]);
+    let _first = Client::painter(&server, "Sam").await;
+    let mut second = Client::painter(&server, "Alex").await;
+    let layout = second.expect(LAYOUT).await;
+    assert_eq!((layout.int("x"), layout.int("y")), (Some(40), Some(0)));
+    second.send(r#"{"msg": "position"}"#).await;
+    let position = second.expect(POSITION).await;
+    assert_eq!(
+        (position.int("col"), position.int("row")),
+        (Some(1), Some(0))
+    );
+    assert_eq!(position.int("x"), None);
+}
+
+#[tokio::test]
+async fn test_admin_commands_stay_in_their_room() {
+    let server = Server::start(&["--admin-password", "pw"]);
+    let mut painter = Client::hello(
+        &server,
+        PAINTER,
+        r#""name": "Sam", "url": "", "room": "lab""#,
+    )
+    .await;
+    painter.expect(SIZE).await;
+    let mut admin = Client::hello(&server, "admin", r#""password": "pw""#).await;
+    admin.expect(SIZE).await;
+
+    admin
+        .send(r#"{"msg": "admin", "cmd": "list", "room": "lab"}"#)
+        .await;
+    let list = admin.expect("admin").await;
+    let id = jsonic::parse(&list.0).unwrap()["clients"][0]["id"]
+        .as_i128()
+        .unwrap();
+    // The painter isn't in the admin's room, so must be kicked from its own
+    admin
+        .send(&format!(
+            r#"{{"msg": "admin", "cmd": "kick", "id": {}}}"#,
+            id
+        ))
+        .await;
+    assert_eq!(admin.expect("admin").await.bool("ok"), Some(false));
+    admin
+        .send(&format!(
+            r#"{{"msg": "admin", "cmd": "kick", "id": {}, "room": "lab"}}"#,
+            id
+        ))
+        .await;
+    assert_eq!(admin.expect("admin").await.bool("ok"), Some(true));
+    painter.expect(ERROR).await;
+}
+
+/// The id of the first painter an admin can see in its room
+async fn painter_id(admin: &mut Client) -> i128 {
+    admin.send(r#"{"msg": "admin", "cmd": "list"}"#).await;
+    let list = admin.expect("admin").await;
+    let clients = jsonic::parse(&list.0).unwrap();
+    let painter = clients["clients"]
+        .elements()
+        .unwrap()
+        .find(|client| client["role"].as_str() == Some(PAINTER))
+        .unwrap();
+    painter["id"].as_i128().unwrap()
+}
+
+#[tokio::test]
+async fn test_admin_bans_ips_only_when_asked() {
+    let server = Server::start(&["--admin-password", "pw"]);
+    let mut admin = Client::hello(&server, "admin", r#""password": "pw""#).await;
+    admin.expect(SIZE).await;
+
+    let _sam = Client::painter(&server, "Sam").await;
+    let id = painter_id(&mut admin).await;
+    admin
+        .send(&format!(
+            r#"{{"msg": "admin", "cmd": "ban", "id": {}}}"#,
+            id
+        ))
+        .await;
+    assert_eq!(admin.expect("admin").await.bool("ok"), Some(true));
+    // Everyone here shares an address, and only Sam's name is banned
+    let _alex = Client::painter(&server, "Alex").await;
+
+    let id = painter_id(&mut admin).await;
+    admin
+        .send(&format!(
+            r#"{{"msg": "admin", "cmd": "ban", "id": {}, "ip": true}}"#,
+            id
+        ))
+        .await;
+    assert_eq!(admin.expect("admin").await.bool("ok"), Some(true));
+    let mut banned = Client::connect(&server).await;
+    assert_eq!(
+        banned.expect(ERROR).await.str("error").as_deref(),
+        Some("Banned")
+    );
+}
+
+#[tokio::test]
+async fn test_only_canvases_get_the_image() {
+    let server = Server::start(&["--token", "secret"]);
+    let mut stranger = Client::connect(&server).await;
+    stranger.expect(WHO_ARE_YOU).await;
+    stranger.send(r#"{"msg": "p"}"#).await;
+    let error = stranger.expect(ERROR).await;
+    assert_eq!(
+        error.str("error").as_deref(),
+        Some("Only canvases may ask for the image")
+    );
+
+    let mut canvas = Client::hello(&server, "canvas", r#""token": "secret""#).await;
+    canvas.expect(SIZE).await;
+    let mut painter = Client::hello(
+        &server,
+        PAINTER,
+        r#""name": "Sam", "url": "", "token": "secret""#,
+    )
+    .await;
+    painter.expect(SIZE).await;
+    canvas.send(r#"{"msg": "p"}"#).await;
+    canvas.expect_binary().await;
+}
+
+#[tokio::test]
+async fn test_disallowed_origin_gets_no_image() {
+    let server = Server::start(&["--allow-origin", "https://gallery.example"]);
+    let _painter = Client::painter(&server, "Sam").await;
+    let mut page = Client::connect_from(&server, "https://elsewhere.example").await;
+    page.expect(WHO_ARE_YOU).await;
+    // Skipping the handshake doesn't get around the origin check
+    page.send(r#"{"msg": "p"}"#).await;
+    page.expect(ERROR).await;
+    page.send(r#"{"msg": "?", "?": "canvas"}"#).await;
+    assert_eq!(
+        page.expect(ERROR).await.str("error").as_deref(),
+        Some("Origin not allowed")
+    );
+    page.closed().await;
+
+    let mut gallery = Client::connect_from(&server, "https://gallery.example").await;
+    gallery.expect(WHO_ARE_YOU).await;
+    gallery.send(r#"{"msg": "?", "?": "canvas"}"#).await;
+    gallery.expect(SIZE).await;
+    gallery.send(r#"{"msg": "p"}"#).await;
+    gallery.expect_binary().await;
+}
+
+#[tokio::test]
+async fn test_naughty_painter_is_warned_then_kicked() {
+    // Without decay every offence counts in full
+    let server = Server::start(&["--naughty-decay", "0", "--message-rate", "0"]);
+    let mut painter = Client::painter(&server, "Sam").await;
+    for naughty in 1..=50 {
+        painter.send(r#"{"msg": "dance"}"#).await;
+        let error = painter.expect(ERROR).await;
+        assert_eq!(error.int("naughty"), Some(naughty));
+        let message = error.str("error").unwrap();
+        match naughty {
+            25 => assert!(message.starts_with("COOLDOWN"), "{}", message),
+            50 => assert!(message.starts_with("FINAL WARNING"), "{}", message),
+            _ => assert_eq!(message, "Unknown message: dance"),
+        }
+    }
+    painter.send(r#"{"msg": "dance"}"#).await;
+    painter.closed().await;
+}
+
+#[tokio::test]
+async fn test_65th_painter_waits_for_a_tile() {
+    // Without resuming, a leaving painter's tile is freed straight away
+    let server = Server::start(&["--resume-grace-ms", "0"]);
+    let mut painters = Vec::new();
+    for n in 0..64 {
+        painters.push(Client::painter(&server, &format!("painter {}", n)).await);
+    }
+    let mut first = Client::hello(&server, PAINTER, r#""name": "first", "url": """#).await;
+    assert_eq!(first.expect(FULL).await.int("position"), Some(1));
+    let mut second = Client::hello(&server, PAINTER, r#""name": "second", "url": """#).await;
+    assert_eq!(second.expect(FULL).await.int("position"), Some(2));
+
+    // Someone leaves: the first in line takes their tile and the second moves up
+    drop(painters.pop());
+    let size = first.expect(SIZE).await;
+    assert_eq!(size.int("w"), Some(TILE_PIXELS as i128));
+    first.expect(SEND_ME_PIXELS).await;
+    assert_eq!(second.expect(FULL).await.int("position"), Some(1));
+
+    let mut canvas = Client::canvas(&server).await;
+    assert_eq!(canvas.expect("layout").await.int("painters"), Some(64));
+}
+
+/// Fill a room and queue one more painter
+async fn full_room(server: &Server) -> (Vec<Client>, Client) {
+    let mut painters = Vec::new();
+    for n in 0..64 {
+        painters.push(Client::painter(server, &format!("painter {}", n)).await);
+    }
+    let mut waiting = Client::hello(server, PAINTER, r#""name": "waiting", "url": """#).await;
+    assert_eq!(waiting.expect(FULL).await.int("position"), Some(1));
+    (painters, waiting)
+}
+
+#[tokio::test]
+async fn test_longest_serving_painter_yields_its_turn() {
+    let server = Server::start(&["--turn-secs", "1"]);
+    let (mut painters, mut waiting) = full_room(&server).await;
+    waiting.expect(SIZE).await;
+    let yielded = &mut painters[0];
+    assert_eq!(yielded.expect(FULL).await.int("position"), Some(1));
+
+    // A frame and a question already on their way as the turn ended aren't held against it
+    yielded.send_binary(vec![0; TILE_BYTES]).await;
+    yielded.send(r#"{"msg": "position"}"#).await;
+    yielded.send(r#"{"msg": "dance"}"#).await;
+    let error = yielded.expect(ERROR).await;
+    assert_eq!(
+        error.str("error").as_deref(),
+        Some("Unknown message: dance")
+    );
+    assert_eq!(error.int("naughty"), Some(1));
+}
+
+#[tokio::test]
+async fn test_painters_are_warned_before_their_turn_ends() {
+    let server = Server::start(&["--turn-secs", "11"]);
+    let (mut painters, _waiting) = full_room(&server).await;
+    assert_eq!(painters[0].expect(TURN).await.int("seconds_left"), Some(10));
+}